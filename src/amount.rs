@@ -0,0 +1,150 @@
+use anyhow::{anyhow, Context, Result};
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+use crate::config::EngineConfig;
+
+/// Stand-ins for a missing amount accepted under `null_tokens`, compared
+/// case-insensitively.
+const NULL_TOKENS: [&str; 3] = ["null", "nil", "none"];
+
+/// Leading symbols stripped from an amount under `lenient_amounts`.
+const CURRENCY_SYMBOLS: [char; 4] = ['$', '€', '£', '¥'];
+
+/// Parses the raw `amount` column when one of the non-standard input syntaxes is
+/// enabled. With none enabled the column is deserialized straight into a `Decimal`
+/// and this is never called.
+pub fn parse_amount(raw: &str, config: &EngineConfig) -> Result<Option<Decimal>> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Ok(None);
+    }
+    if config.null_tokens && NULL_TOKENS.iter().any(|t| raw.eq_ignore_ascii_case(t)) {
+        return Ok(None);
+    }
+    let lenient;
+    let raw = if config.lenient_amounts {
+        lenient = strip_formatting(raw)?;
+        lenient.as_str()
+    } else {
+        raw
+    };
+    if config.fraction_amounts {
+        if let Some((numerator, denominator)) = raw.split_once('/') {
+            return parse_fraction(numerator, denominator, config.precision).map(Some);
+        }
+    }
+    Decimal::from_str(raw)
+        .map(Some)
+        .with_context(|| format!("Invalid amount '{}'", raw))
+}
+
+/// `raw` without a leading currency symbol (after any sign) or thousands
+/// separators, which must group the integer digits in threes.
+fn strip_formatting(raw: &str) -> Result<String> {
+    let (sign, unsigned) = match raw.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", raw),
+    };
+    let unsigned = unsigned
+        .strip_prefix(CURRENCY_SYMBOLS)
+        .unwrap_or(unsigned)
+        .trim_start();
+    let (integer, fraction) = match unsigned.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (unsigned, None),
+    };
+    if integer.contains(',') {
+        let mut groups = integer.split(',');
+        let first = groups.next().unwrap_or_default();
+        let digits = |group: &str| group.bytes().all(|b| b.is_ascii_digit());
+        let well_grouped = (1..=3).contains(&first.len())
+            && digits(first)
+            && groups.all(|group| group.len() == 3 && digits(group));
+        if !well_grouped {
+            return Err(anyhow!("Invalid thousands separators in amount '{}'", raw));
+        }
+    }
+    let mut stripped = format!("{}{}", sign, integer.replace(',', ""));
+    if let Some(fraction) = fraction {
+        stripped.push('.');
+        stripped.push_str(fraction);
+    }
+    Ok(stripped)
+}
+
+/// `a/b` divided out and rounded (banker's rounding) to `precision` places.
+fn parse_fraction(numerator: &str, denominator: &str, precision: u32) -> Result<Decimal> {
+    let parse = |part: &str| {
+        Decimal::from_str(part.trim())
+            .with_context(|| format!("Invalid fraction amount '{}/{}'", numerator, denominator))
+    };
+    let (numerator, denominator) = (parse(numerator)?, parse(denominator)?);
+    numerator
+        .checked_div(denominator)
+        .map(|value| value.round_dp(precision))
+        .ok_or_else(|| anyhow!("Invalid fraction amount: zero denominator"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn fractions() -> EngineConfig {
+        EngineConfig {
+            fraction_amounts: true,
+            ..EngineConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_fraction_amounts() -> Result<()> {
+        assert_eq!(parse_amount("1/2", &fractions())?, Some(dec!(0.5)));
+        assert_eq!(parse_amount("1/3", &fractions())?, Some(dec!(0.3333)));
+        assert_eq!(parse_amount("2.5", &fractions())?, Some(dec!(2.5)));
+        assert_eq!(parse_amount("", &fractions())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fraction_zero_denominator() {
+        let err = parse_amount("1/0", &fractions()).unwrap_err();
+        assert!(err.to_string().contains("zero denominator"));
+    }
+
+    #[test]
+    fn test_null_tokens() -> Result<()> {
+        let config = EngineConfig {
+            null_tokens: true,
+            ..EngineConfig::default()
+        };
+        for token in ["null", "NULL", "Nil", "none"] {
+            assert_eq!(parse_amount(token, &config)?, None);
+        }
+        assert_eq!(parse_amount("1.25", &config)?, Some(dec!(1.25)));
+        assert!(parse_amount("nullable", &config).is_err());
+        assert!(parse_amount("null", &EngineConfig::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lenient_amounts() -> Result<()> {
+        let config = EngineConfig {
+            lenient_amounts: true,
+            ..EngineConfig::default()
+        };
+        assert_eq!(parse_amount("$1,234.56", &config)?, Some(dec!(1234.56)));
+        assert_eq!(parse_amount("€1,000,000", &config)?, Some(dec!(1000000)));
+        assert_eq!(parse_amount("-£12.5", &config)?, Some(dec!(-12.5)));
+        assert_eq!(parse_amount("7.25", &config)?, Some(dec!(7.25)));
+        assert!(parse_amount("1,23.4", &config).is_err());
+        assert!(parse_amount("$1,234.56", &EngineConfig::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fraction_requires_flag() {
+        assert!(parse_amount("1/2", &EngineConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::{Context, Result};
+use payments_engine::models::{ClientBalance, OperationRecord};
+use payments_engine::output::format_decimal;
+use std::fs::File;
+use std::io::BufWriter;
+
+const COLUMNS: [&str; 8] = [
+    "seq",
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "available",
+    "held",
+    "locked",
+];
+
+/// The `--audit` trail: one CSV line per applied operation, numbered in the order
+/// applied, with the client's balance right after it.
+///
+/// Amounts are written to the same precision as the balances output.
+pub struct AuditLog {
+    writer: csv::Writer<BufWriter<File>>,
+    precision: u32,
+    seq: u64,
+    /// The first write error, reported by `finish`: the engine's observer cannot
+    /// fail, so `record` has nowhere to return it.
+    error: Option<csv::Error>,
+}
+
+impl AuditLog {
+    pub fn create(path: &str, precision: u32) -> Result<Self> {
+        let file = File::create(path).context("Failed to create audit file")?;
+        let mut writer = csv::Writer::from_writer(BufWriter::new(file));
+        writer
+            .write_record(COLUMNS)
+            .context("Failed to write audit header")?;
+        Ok(Self {
+            writer,
+            precision,
+            seq: 0,
+            error: None,
+        })
+    }
+
+    /// Appends `record`; `name` is the client's name under `--string-clients`.
+    pub fn record(
+        &mut self,
+        record: &OperationRecord,
+        balance: &ClientBalance,
+        name: Option<&str>,
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+        self.seq += 1;
+        let amount = |value| format_decimal(value, self.precision);
+        let row = [
+            self.seq.to_string(),
+            record.r#type.name().to_string(),
+            name.map_or_else(|| record.client.to_string(), str::to_string),
+            record.tx.to_string(),
+            record.amount.map(amount).unwrap_or_default(),
+            amount(balance.available),
+            amount(balance.held),
+            balance.locked.to_string(),
+        ];
+        if let Err(err) = self.writer.write_record(&row) {
+            self.error = Some(err);
+        }
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err).context("Failed to write audit trail");
+        }
+        self.writer.flush().context("Failed to write audit trail")
+    }
+}
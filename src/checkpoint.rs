@@ -0,0 +1,72 @@
+//! Saving an engine's ledger mid-run so a long run can be resumed later.
+
+use anyhow::{Context, Result};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+
+use crate::engine::RunCounts;
+use crate::models::{ClientBalance, TransactionState};
+
+/// The state `Engine::snapshot` captures and `Engine::restore` resumes from: balances,
+/// the transaction log and open disputes, plus what the engine needs to keep treating
+/// later rows the same way (window order, settled txs, counts).
+///
+/// Amounts are kept as exact decimal strings, so a round trip loses no precision.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SerializedState {
+    pub client_balances: HashMap<u16, BalanceState>,
+    pub first_seen: Vec<u16>,
+    pub transaction_log: HashMap<u32, TransactionState>,
+    pub log_order: VecDeque<u32>,
+    pub dispute_tracker: HashMap<u32, Decimal>,
+    pub settled: HashMap<u32, u16>,
+    /// Absent from checkpoints written before `DisputeAlreadyUsed` existed.
+    #[serde(default)]
+    pub resolved: HashSet<u32>,
+    pub applied_per_client: HashMap<u16, u64>,
+    pub counts: RunCounts,
+}
+
+/// A `ClientBalance` as checkpointed. `ClientBalance`'s own `Serialize` is the
+/// rounded output form, so it cannot be read back.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BalanceState {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub locked: bool,
+    pub lock_reason: Option<u32>,
+}
+
+impl From<&ClientBalance> for BalanceState {
+    fn from(balance: &ClientBalance) -> Self {
+        Self {
+            available: balance.available,
+            held: balance.held,
+            locked: balance.locked,
+            lock_reason: balance.lock_reason,
+        }
+    }
+}
+
+impl From<BalanceState> for ClientBalance {
+    fn from(state: BalanceState) -> Self {
+        Self {
+            available: state.available,
+            held: state.held,
+            locked: state.locked,
+            lock_reason: state.lock_reason,
+        }
+    }
+}
+
+impl SerializedState {
+    pub fn write_json(&self, writer: impl Write) -> Result<()> {
+        serde_json::to_writer(writer, self).context("Failed to write checkpoint")
+    }
+
+    pub fn read_json(reader: impl Read) -> Result<Self> {
+        serde_json::from_reader(reader).context("Failed to read checkpoint")
+    }
+}
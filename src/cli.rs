@@ -0,0 +1,354 @@
+use anyhow::{anyhow, Context, Result};
+
+use payments_engine::config::{DisputeHoldPolicy, EngineConfig, ProcessingMode, WithdrawalFee};
+use payments_engine::output::{self, Column, OutputFormat, SortKey, SummaryTarget};
+
+/// Rows between `--progress` reports.
+pub const PROGRESS_EVERY: u64 = 1_000_000;
+
+/// Input path meaning standard input; also the default when no path is given.
+pub const STDIN: &str = "-";
+
+pub const USAGE: &str = "Usage: cargo run -- [OPTIONS] [<input.csv>... | -]\n       \
+                         cargo run -- merge-outputs [OPTIONS] <balances.csv>...\n       \
+                         cargo run -- --serve <addr> [OPTIONS]\n\
+                         Transactions are read from stdin when no input is given.";
+
+/// What a run does with its positional arguments.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Command {
+    /// Process the transaction files in `Options::inputs`.
+    #[default]
+    Process,
+    /// Combine balances CSVs from earlier runs without reprocessing transactions.
+    MergeOutputs(Vec<String>),
+    /// Apply rows streamed over TCP connections to this address (see `server`).
+    Serve(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Options {
+    pub command: Command,
+    /// Transaction files, processed in order through one engine; `STDIN` reads
+    /// standard input.
+    pub inputs: Vec<String>,
+    pub max_line_bytes: Option<usize>,
+    pub max_input_bytes: Option<u64>,
+    pub lock_file: Option<String>,
+    pub read_buffer_bytes: Option<usize>,
+    /// Field separator for CSV input and output; `None` means a comma.
+    pub delimiter: Option<u8>,
+    pub format: OutputFormat,
+    pub output: Option<String>,
+    pub json_output: Option<String>,
+    /// `--audit`: where to log each applied operation with the balance it left.
+    pub audit: Option<String>,
+    pub hash_chain_file: Option<String>,
+    pub emit_partial_on_error: bool,
+    pub auto_resolve_open: bool,
+    pub report_skipped: bool,
+    pub progress: bool,
+    pub validate_only: bool,
+    pub anonymize: bool,
+    pub truncate: bool,
+    pub pad_client: Option<usize>,
+    pub sort_by: SortKey,
+    pub descending: bool,
+    pub group_by_locked: bool,
+    pub max_output_rows: Option<usize>,
+    pub columns: Option<Vec<Column>>,
+    pub with_stats: bool,
+    pub summary: Option<SummaryTarget>,
+    pub verbose: bool,
+    pub report_fees: bool,
+    pub precision: Option<u32>,
+    pub anonymize_map: Option<String>,
+    pub engine: EngineConfig,
+}
+
+impl Options {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut options = Options::default();
+        let mut inputs = Vec::new();
+        let mut serve = None;
+
+        let mut args = args.into_iter().peekable();
+        let mut merge_inputs = Vec::new();
+        let merging = args.next_if(|arg| arg == "merge-outputs").is_some();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--max-line-bytes" => {
+                    options.max_line_bytes = Some(parse_value(&mut args, &arg)?);
+                }
+                "--max-input-bytes" => {
+                    options.max_input_bytes = Some(parse_value(&mut args, &arg)?);
+                }
+                "--lock-file" => options.lock_file = Some(parse_value(&mut args, &arg)?),
+                "--read-buffer-bytes" => {
+                    options.read_buffer_bytes = Some(parse_value(&mut args, &arg)?);
+                }
+                "--delimiter" => {
+                    let value: String = parse_value(&mut args, &arg)?;
+                    options.delimiter = Some(
+                        parse_delimiter(&value)
+                            .with_context(|| format!("Invalid value '{}' for {}", value, arg))?,
+                    );
+                }
+                "--format" => options.format = parse_value(&mut args, &arg)?,
+                "-o" | "--output" => options.output = Some(parse_value(&mut args, &arg)?),
+                "--json-output" => options.json_output = Some(parse_value(&mut args, &arg)?),
+                "--audit" => options.audit = Some(parse_value(&mut args, &arg)?),
+                "--max-disputed-per-client" => {
+                    options.engine.max_disputed_amount_per_client =
+                        Some(parse_value(&mut args, &arg)?);
+                }
+                "--hash-chain" => options.engine.hash_chain = true,
+                "--hash-chain-file" => {
+                    options.engine.hash_chain = true;
+                    options.hash_chain_file = Some(parse_value(&mut args, &arg)?);
+                }
+                "--string-clients" => options.engine.string_clients = true,
+                "--emit-partial-on-error" => options.emit_partial_on_error = true,
+                "--auto-resolve-open" => options.auto_resolve_open = true,
+                "--report-skipped" => options.report_skipped = true,
+                "--progress" => options.progress = true,
+                "--validate-only" => options.validate_only = true,
+                "--serve" => serve = Some(parse_value(&mut args, &arg)?),
+                "--reject-zero-ids" => options.engine.reject_zero_ids = true,
+                "--clients" => {
+                    let list: String = parse_value(&mut args, &arg)?;
+                    let clients = list
+                        .split(',')
+                        .map(|client| client.trim().parse())
+                        .collect::<Result<_, _>>()
+                        .with_context(|| format!("Invalid value '{}' for {}", list, arg))?;
+                    options.engine.clients = Some(clients);
+                }
+                "--verify" => options.engine.ledger_stats = true,
+                "--strict" => options.engine.processing_mode = ProcessingMode::Strict,
+                "--anonymize" => options.anonymize = true,
+                "--anonymize-map" => {
+                    options.anonymize = true;
+                    options.anonymize_map = Some(parse_value(&mut args, &arg)?);
+                }
+                "--null-tokens" => options.engine.null_tokens = true,
+                "--fraction-amounts" => options.engine.fraction_amounts = true,
+                "--lenient-amounts" => options.engine.lenient_amounts = true,
+                "--include-held-in-withdrawable" => {
+                    options.engine.include_held_in_withdrawable = true;
+                }
+                "--truncate" => options.truncate = true,
+                "--withdrawal-grace" => {
+                    options.engine.withdrawal_grace = parse_value(&mut args, &arg)?;
+                }
+                "--withdrawal-fee" => {
+                    options.engine.withdrawal_fee = parse_value(&mut args, &arg)?;
+                }
+                "--partial-withdrawals" => options.engine.partial_withdrawals = true,
+                "--max-withdrawal" => {
+                    options.engine.max_withdrawal = Some(parse_value(&mut args, &arg)?);
+                }
+                "--report-fees" => {
+                    options.report_fees = true;
+                    options.engine.client_flows = true;
+                }
+                "--sort-by" => options.sort_by = parse_value(&mut args, &arg)?,
+                "--desc" => options.descending = true,
+                "--columns" => {
+                    let list: String = parse_value(&mut args, &arg)?;
+                    options.columns = Some(
+                        output::parse_columns(&list)
+                            .with_context(|| format!("Invalid value '{}' for {}", list, arg))?,
+                    );
+                }
+                "--dispute-window" => {
+                    options.engine.dispute_window = Some(parse_value(&mut args, &arg)?);
+                }
+                "--max-tx-per-client" => {
+                    let max: u64 = parse_value(&mut args, &arg)?;
+                    options.engine.max_tx_per_client = (max > 0).then_some(max);
+                }
+                "--max-output-rows" => {
+                    options.max_output_rows = Some(parse_value(&mut args, &arg)?);
+                }
+                "--group-by-locked" => options.group_by_locked = true,
+                "--precision" => {
+                    let precision = parse_value(&mut args, &arg)?;
+                    if precision > output::MAX_PRECISION {
+                        return Err(anyhow!(
+                            "--precision must be between 0 and {}",
+                            output::MAX_PRECISION
+                        ));
+                    }
+                    options.precision = Some(precision);
+                    options.engine.precision = precision;
+                }
+                "--excess-precision" => {
+                    options.engine.excess_precision = parse_value(&mut args, &arg)?;
+                }
+                "--pad-client" => options.pad_client = Some(parse_value(&mut args, &arg)?),
+                "--dispute-hold" => {
+                    options.engine.dispute_hold_policy = parse_value(&mut args, &arg)?;
+                }
+                "--dispute-policy" => {
+                    options.engine.dispute_policy = parse_value(&mut args, &arg)?;
+                }
+                "--match-dispute-amount" => options.engine.match_dispute_amount = true,
+                "--partial-disputes" => options.engine.partial_disputes = true,
+                "--deposit-disputes-only" => options.engine.deposit_disputes_only = true,
+                "--reject-overdispute" => {
+                    options.engine.dispute_hold_policy = DisputeHoldPolicy::RejectOverdispute;
+                }
+                "--summary" => options.summary = Some(parse_value(&mut args, &arg)?),
+                "--with-stats" => {
+                    options.with_stats = true;
+                    options.engine.client_flows = true;
+                }
+                "--verbose" => {
+                    options.verbose = true;
+                    options.engine.client_flows = true;
+                }
+                "--client-hash" => options.engine.client_hash = true,
+                // The header is always checked now; still accepted for existing scripts.
+                "--require-header" => eprintln!(
+                    "warning: --require-header is deprecated and has no effect; \
+                     the input header is always checked"
+                ),
+                "--amount-stats" => options.engine.amount_stats = true,
+                flag if flag.starts_with("--") => {
+                    return Err(anyhow!("Unknown option '{}'\n{}", flag, USAGE));
+                }
+                _ if merging => merge_inputs.push(arg),
+                _ => inputs.push(arg),
+            }
+        }
+
+        if merging {
+            if merge_inputs.is_empty() {
+                return Err(anyhow!(USAGE));
+            }
+            options.command = Command::MergeOutputs(merge_inputs);
+        } else if let Some(addr) = serve {
+            if !inputs.is_empty() {
+                return Err(anyhow!(
+                    "--serve reads from the socket and takes no input files"
+                ));
+            }
+            options.command = Command::Serve(addr);
+        } else {
+            if inputs.is_empty() {
+                inputs.push(STDIN.to_string());
+            }
+            if inputs.iter().filter(|path| *path == STDIN).count() > 1 {
+                return Err(anyhow!("Standard input ('-') can only be read once"));
+            }
+            options.inputs = inputs;
+        }
+        if options.format == OutputFormat::Parquet && options.output.is_none() {
+            return Err(anyhow!("--format parquet requires --output <path>"));
+        }
+        if let Some(columns) = &options.columns {
+            if options.format != OutputFormat::Csv {
+                return Err(anyhow!("--columns only applies to --format csv"));
+            }
+            if columns.contains(&Column::NetFlow) && !options.with_stats {
+                return Err(anyhow!("--columns net_flow requires --with-stats"));
+            }
+            let activity = [Column::Deposits, Column::Withdrawals, Column::Volume];
+            if columns.iter().any(|c| activity.contains(c)) && !options.verbose {
+                return Err(anyhow!(
+                    "--columns deposits, withdrawals and volume require --verbose"
+                ));
+            }
+            if columns.contains(&Column::Fees) && !options.report_fees {
+                return Err(anyhow!("--columns fees requires --report-fees"));
+            }
+            if columns.contains(&Column::ClientHash) && !options.engine.client_hash {
+                return Err(anyhow!("--columns client_hash requires --client-hash"));
+            }
+        }
+        if options.summary == Some(SummaryTarget::Footer) && options.format != OutputFormat::Csv {
+            return Err(anyhow!("--summary footer only applies to --format csv"));
+        }
+        if options.pad_client.is_some() && options.format != OutputFormat::Csv {
+            return Err(anyhow!("--pad-client only applies to --format csv"));
+        }
+        if options.engine.withdrawal_grace.is_sign_negative() {
+            return Err(anyhow!("--withdrawal-grace must not be negative"));
+        }
+        if options
+            .engine
+            .max_withdrawal
+            .is_some_and(|max| max.is_sign_negative())
+        {
+            return Err(anyhow!("--max-withdrawal must not be negative"));
+        }
+        if options.format == OutputFormat::Parquet && options.with_stats {
+            return Err(anyhow!(
+                "--with-stats is not supported with --format parquet"
+            ));
+        }
+        if options.format == OutputFormat::Parquet && options.verbose {
+            return Err(anyhow!("--verbose is not supported with --format parquet"));
+        }
+        if options.format == OutputFormat::Parquet && options.report_fees {
+            return Err(anyhow!(
+                "--report-fees is not supported with --format parquet"
+            ));
+        }
+        if options.engine.partial_withdrawals
+            && options.engine.withdrawal_fee != WithdrawalFee::None
+        {
+            return Err(anyhow!(
+                "--partial-withdrawals cannot be combined with --withdrawal-fee"
+            ));
+        }
+        if options.engine.partial_disputes && options.engine.match_dispute_amount {
+            return Err(anyhow!(
+                "--partial-disputes cannot be combined with --match-dispute-amount"
+            ));
+        }
+        if options.report_fees && options.engine.withdrawal_fee == WithdrawalFee::None {
+            return Err(anyhow!("--report-fees requires --withdrawal-fee"));
+        }
+        if options.engine.clients.is_some() && options.engine.string_clients {
+            return Err(anyhow!(
+                "--clients cannot be combined with --string-clients"
+            ));
+        }
+        if options.format == OutputFormat::Parquet && options.engine.string_clients {
+            return Err(anyhow!(
+                "--string-clients is not supported with --format parquet"
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// A single ASCII character, or `tab` / `\t` for a tab.
+fn parse_delimiter(value: &str) -> Result<u8> {
+    match value {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ => match value.as_bytes() {
+            [byte] if byte.is_ascii() && !matches!(byte, b'"' | b'\n' | b'\r') => Ok(*byte),
+            _ => Err(anyhow!(
+                "Unknown delimiter '{}' (expected a single character or tab)",
+                value
+            )),
+        },
+    }
+}
+
+fn parse_value<T>(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    let value = args
+        .next()
+        .ok_or_else(|| anyhow!("Missing value for {}", flag))?;
+    value
+        .parse()
+        .map_err(Into::into)
+        .with_context(|| format!("Invalid value '{}' for {}", value, flag))
+}
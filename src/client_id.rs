@@ -0,0 +1,39 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+/// Maps alphanumeric client ids onto the engine's `u16` ids.
+///
+/// Used by `--string-clients`: ids are handed out in first-appearance order, so the
+/// engine (and everything keyed by `u16`) is unchanged and names are only needed
+/// again at output time. At most `u16::MAX + 1` distinct names fit.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientInterner {
+    ids: HashMap<String, u16>,
+    names: Vec<String>,
+}
+
+impl ClientInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, name: &str) -> Result<u16> {
+        if let Some(&id) = self.ids.get(name) {
+            return Ok(id);
+        }
+        let id = u16::try_from(self.names.len())
+            .map_err(|_| anyhow!("Too many distinct client ids (limit {})", 1 << 16))?;
+        self.ids.insert(name.to_string(), id);
+        self.names.push(name.to_string());
+        Ok(id)
+    }
+
+    /// The id `name` was interned as, if it has been seen.
+    pub fn id(&self, name: &str) -> Option<u16> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: u16) -> Option<&str> {
+        self.names.get(id as usize).map(String::as_str)
+    }
+}
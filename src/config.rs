@@ -0,0 +1,462 @@
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// What a dispute holds when the client has already spent part of the deposit.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DisputeHoldPolicy {
+    /// Hold the whole amount, even if that drives `available` negative.
+    #[default]
+    HoldFull,
+    /// Hold `min(amount, available)` and report the rest as a `DisputeShortfall`.
+    HoldAvailableOnly,
+    /// Ignore the dispute when `available < amount`, so `available` never goes
+    /// negative.
+    RejectOverdispute,
+}
+
+impl FromStr for DisputeHoldPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "full" => Ok(DisputeHoldPolicy::HoldFull),
+            "available-only" => Ok(DisputeHoldPolicy::HoldAvailableOnly),
+            "reject" => Ok(DisputeHoldPolicy::RejectOverdispute),
+            _ => Err(anyhow!(
+                "Unknown dispute hold policy '{}' (expected full, available-only or reject)",
+                s
+            )),
+        }
+    }
+}
+
+/// Whether a tx can be disputed again once an earlier dispute on it was resolved.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DisputePolicy {
+    /// A resolve settles the tx for good; a later dispute is skipped as
+    /// `DisputeAlreadyUsed`.
+    #[default]
+    Once,
+    /// A resolve keeps the tx in the log, open to further disputes.
+    Multiple,
+}
+
+impl FromStr for DisputePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "once" => Ok(DisputePolicy::Once),
+            "multiple" => Ok(DisputePolicy::Multiple),
+            _ => Err(anyhow!(
+                "Unknown dispute policy '{}' (expected once or multiple)",
+                s
+            )),
+        }
+    }
+}
+
+/// Charge taken from `available` on top of each successful withdrawal.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WithdrawalFee {
+    #[default]
+    None,
+    Flat(Decimal),
+    /// Percentage of the withdrawn amount, rounded to the engine's `precision`.
+    Percent(Decimal),
+}
+
+impl WithdrawalFee {
+    /// The fee on withdrawing `amount`, or `None` if computing it overflows.
+    pub fn on(&self, amount: Decimal, precision: u32) -> Option<Decimal> {
+        match *self {
+            WithdrawalFee::None => Some(Decimal::ZERO),
+            WithdrawalFee::Flat(fee) => Some(fee),
+            WithdrawalFee::Percent(percent) => Some(
+                amount
+                    .checked_mul(percent)?
+                    .checked_div(Decimal::ONE_HUNDRED)?
+                    .round_dp(precision),
+            ),
+        }
+    }
+}
+
+impl FromStr for WithdrawalFee {
+    type Err = anyhow::Error;
+
+    /// Parses `none`, `flat:<amount>` or `percent:<rate>`.
+    fn from_str(s: &str) -> Result<Self> {
+        let lower = s.to_lowercase();
+        let (kind, value) = lower.split_once(':').unwrap_or((lower.as_str(), ""));
+        let fee = match kind {
+            "none" if value.is_empty() => return Ok(WithdrawalFee::None),
+            "flat" => WithdrawalFee::Flat,
+            "percent" => WithdrawalFee::Percent,
+            _ => {
+                return Err(anyhow!(
+                    "Unknown withdrawal fee '{}' (expected none, flat:<amount> or percent:<rate>)",
+                    s
+                ))
+            }
+        };
+        let value = Decimal::from_str(value)
+            .map_err(|_| anyhow!("Invalid withdrawal fee amount in '{}'", s))?;
+        if value.is_sign_negative() {
+            return Err(anyhow!("Withdrawal fee must not be negative"));
+        }
+        Ok(fee(value))
+    }
+}
+
+/// What happens to a deposit or withdrawal amount with more decimal places than the
+/// engine's `precision`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ExcessPrecision {
+    /// Apply it as given; only the output is rounded.
+    #[default]
+    Accept,
+    /// Skip the operation as `ExcessivePrecision`.
+    Reject,
+    /// Round the amount to `precision` places (banker's rounding) before applying it.
+    Round,
+}
+
+impl FromStr for ExcessPrecision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "accept" => Ok(ExcessPrecision::Accept),
+            "reject" => Ok(ExcessPrecision::Reject),
+            "round" => Ok(ExcessPrecision::Round),
+            _ => Err(anyhow!(
+                "Unknown excess precision handling '{}' (expected accept, reject or round)",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens to an operation that parses but cannot be applied (see `SkipReason`).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ProcessingMode {
+    /// Record it as skipped and carry on.
+    #[default]
+    Lenient,
+    /// Abort processing with an error naming the tx.
+    Strict,
+}
+
+/// Tunables for `process_transactions`. `Default` reproduces the original behavior.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineConfig {
+    /// Cap on a client's total held funds. A dispute that would push `held` above it
+    /// is skipped as `DisputeAmountCeiling`.
+    pub max_disputed_amount_per_client: Option<Decimal>,
+    /// Maintain a rolling hash over every applied operation (see `HashChain`).
+    pub hash_chain: bool,
+    /// Accept arbitrary string client ids, interned to `u16` (see `ClientInterner`).
+    pub string_clients: bool,
+    /// Accept amounts written as fractions (`1/3`), divided out at `precision`.
+    pub fraction_amounts: bool,
+    /// Treat `null`, `nil` and `none` in the amount column as a missing amount.
+    pub null_tokens: bool,
+    /// Accept amounts such as `$1,234.56`: a leading currency symbol and thousands
+    /// separators are stripped before parsing.
+    pub lenient_amounts: bool,
+    /// Decimal places used when an amount has to be rounded on input, and the most a
+    /// deposit or withdrawal may carry under `excess_precision`.
+    pub precision: u32,
+    pub excess_precision: ExcessPrecision,
+    /// Let withdrawals draw against `available + held` instead of `available` alone.
+    pub include_held_in_withdrawable: bool,
+    /// Shortfall tolerated on a withdrawal, letting `available` dip this far below
+    /// zero to absorb sub-cent rounding differences.
+    pub withdrawal_grace: Decimal,
+    /// Fee deducted from `available` with each withdrawal; the withdrawal must cover
+    /// amount plus fee.
+    pub withdrawal_fee: WithdrawalFee,
+    /// Largest amount a single withdrawal may take; larger ones are skipped as
+    /// `ExceedsWithdrawalLimit`. `None` is uncapped.
+    pub max_withdrawal: Option<Decimal>,
+    /// Let a withdrawal over the withdrawable balance take what there is, reporting
+    /// the rest as a `WithdrawalShortfall`, instead of skipping it. Only applies
+    /// without a `withdrawal_fee`.
+    pub partial_withdrawals: bool,
+    /// Profile deposit and withdrawal amounts (see `AmountSummary`).
+    pub amount_stats: bool,
+    /// Track per-client deposit and withdrawal totals (see `ClientFlows`).
+    pub client_flows: bool,
+    /// Track the per-client totals `invariants::verify_invariants` checks against
+    /// (see `LedgerStats`).
+    pub ledger_stats: bool,
+    pub dispute_hold_policy: DisputeHoldPolicy,
+    pub dispute_policy: DisputePolicy,
+    /// Skip disputes whose `amount` column is not exactly the disputed amount, as
+    /// `DisputeAmountMismatch`.
+    pub match_dispute_amount: bool,
+    /// Let a dispute's `amount` column contest only that much of the tx; without an
+    /// amount the whole tx is disputed, and an amount of zero is skipped as
+    /// `ZeroDisputeAmount`.
+    pub partial_disputes: bool,
+    /// Only deposits may be disputed; a dispute of a withdrawal is skipped as
+    /// `DisputeOnNonDeposit`.
+    pub deposit_disputes_only: bool,
+    /// Keep a separate `HashChain` per client over that client's applied operations.
+    pub client_hash: bool,
+    pub processing_mode: ProcessingMode,
+    /// Keep only the most recent this many deposits and withdrawals disputable; older
+    /// ones are dropped from the log (unless under dispute) to bound memory.
+    pub dispute_window: Option<usize>,
+    /// Successful operations allowed per client; later ones for that client are
+    /// skipped as `ClientRateLimited`. `None` is unlimited.
+    pub max_tx_per_client: Option<u64>,
+    /// Process only these clients; rows for any other client are ignored, neither
+    /// applied nor reported as skipped. `None` processes every client.
+    pub clients: Option<HashSet<u16>>,
+    /// Treat client 0 and tx 0 as reserved, skipping such rows as `ReservedId`. Under
+    /// `string_clients` only tx 0 is reserved, since client names have no id 0.
+    pub reject_zero_ids: bool,
+}
+
+impl EngineConfig {
+    pub fn builder() -> EngineConfigBuilder {
+        EngineConfigBuilder::default()
+    }
+
+    /// Whether the amount column needs `amount::parse_amount` rather than plain
+    /// `Decimal` deserialization.
+    pub fn custom_amount_syntax(&self) -> bool {
+        self.fraction_amounts || self.null_tokens || self.lenient_amounts
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            max_disputed_amount_per_client: None,
+            hash_chain: false,
+            string_clients: false,
+            fraction_amounts: false,
+            null_tokens: false,
+            lenient_amounts: false,
+            precision: 4,
+            excess_precision: ExcessPrecision::Accept,
+            include_held_in_withdrawable: false,
+            withdrawal_grace: Decimal::ZERO,
+            withdrawal_fee: WithdrawalFee::None,
+            max_withdrawal: None,
+            partial_withdrawals: false,
+            amount_stats: false,
+            client_flows: false,
+            ledger_stats: false,
+            dispute_hold_policy: DisputeHoldPolicy::HoldFull,
+            dispute_policy: DisputePolicy::Once,
+            match_dispute_amount: false,
+            partial_disputes: false,
+            deposit_disputes_only: false,
+            client_hash: false,
+            processing_mode: ProcessingMode::Lenient,
+            dispute_window: None,
+            max_tx_per_client: None,
+            reject_zero_ids: false,
+            clients: None,
+        }
+    }
+}
+
+/// Builds an `EngineConfig` one setting at a time, starting from the defaults:
+/// `EngineConfig::builder().strict(true).precision(2).build()`. Each setter takes
+/// the field's value; optional limits are set by passing the limit itself.
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfigBuilder {
+    config: EngineConfig,
+}
+
+impl EngineConfigBuilder {
+    /// Shorthand for `processing_mode(ProcessingMode::Strict)` (or `Lenient`).
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.config.processing_mode = if strict {
+            ProcessingMode::Strict
+        } else {
+            ProcessingMode::Lenient
+        };
+        self
+    }
+
+    pub fn max_disputed_amount_per_client(mut self, value: Decimal) -> Self {
+        self.config.max_disputed_amount_per_client = Some(value);
+        self
+    }
+
+    pub fn hash_chain(mut self, value: bool) -> Self {
+        self.config.hash_chain = value;
+        self
+    }
+
+    pub fn string_clients(mut self, value: bool) -> Self {
+        self.config.string_clients = value;
+        self
+    }
+
+    pub fn fraction_amounts(mut self, value: bool) -> Self {
+        self.config.fraction_amounts = value;
+        self
+    }
+
+    pub fn null_tokens(mut self, value: bool) -> Self {
+        self.config.null_tokens = value;
+        self
+    }
+
+    pub fn lenient_amounts(mut self, value: bool) -> Self {
+        self.config.lenient_amounts = value;
+        self
+    }
+
+    pub fn precision(mut self, value: u32) -> Self {
+        self.config.precision = value;
+        self
+    }
+
+    pub fn excess_precision(mut self, value: ExcessPrecision) -> Self {
+        self.config.excess_precision = value;
+        self
+    }
+
+    pub fn include_held_in_withdrawable(mut self, value: bool) -> Self {
+        self.config.include_held_in_withdrawable = value;
+        self
+    }
+
+    pub fn withdrawal_grace(mut self, value: Decimal) -> Self {
+        self.config.withdrawal_grace = value;
+        self
+    }
+
+    pub fn withdrawal_fee(mut self, value: WithdrawalFee) -> Self {
+        self.config.withdrawal_fee = value;
+        self
+    }
+
+    pub fn amount_stats(mut self, value: bool) -> Self {
+        self.config.amount_stats = value;
+        self
+    }
+
+    pub fn client_flows(mut self, value: bool) -> Self {
+        self.config.client_flows = value;
+        self
+    }
+
+    pub fn ledger_stats(mut self, value: bool) -> Self {
+        self.config.ledger_stats = value;
+        self
+    }
+
+    pub fn dispute_hold_policy(mut self, value: DisputeHoldPolicy) -> Self {
+        self.config.dispute_hold_policy = value;
+        self
+    }
+
+    pub fn dispute_policy(mut self, value: DisputePolicy) -> Self {
+        self.config.dispute_policy = value;
+        self
+    }
+
+    pub fn match_dispute_amount(mut self, value: bool) -> Self {
+        self.config.match_dispute_amount = value;
+        self
+    }
+
+    pub fn partial_disputes(mut self, value: bool) -> Self {
+        self.config.partial_disputes = value;
+        self
+    }
+
+    pub fn deposit_disputes_only(mut self, value: bool) -> Self {
+        self.config.deposit_disputes_only = value;
+        self
+    }
+
+    pub fn client_hash(mut self, value: bool) -> Self {
+        self.config.client_hash = value;
+        self
+    }
+
+    pub fn processing_mode(mut self, value: ProcessingMode) -> Self {
+        self.config.processing_mode = value;
+        self
+    }
+
+    pub fn dispute_window(mut self, value: usize) -> Self {
+        self.config.dispute_window = Some(value);
+        self
+    }
+
+    pub fn max_withdrawal(mut self, value: Decimal) -> Self {
+        self.config.max_withdrawal = Some(value);
+        self
+    }
+
+    pub fn partial_withdrawals(mut self, value: bool) -> Self {
+        self.config.partial_withdrawals = value;
+        self
+    }
+
+    pub fn max_tx_per_client(mut self, value: u64) -> Self {
+        self.config.max_tx_per_client = Some(value);
+        self
+    }
+
+    pub fn clients(mut self, value: HashSet<u16>) -> Self {
+        self.config.clients = Some(value);
+        self
+    }
+
+    pub fn reject_zero_ids(mut self, value: bool) -> Self {
+        self.config.reject_zero_ids = value;
+        self
+    }
+
+    pub fn build(self) -> EngineConfig {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_builder() {
+        assert_eq!(EngineConfig::builder().build(), EngineConfig::default());
+
+        let config = EngineConfig::builder()
+            .strict(true)
+            .precision(2)
+            .withdrawal_fee(WithdrawalFee::Flat(dec!(0.5)))
+            .dispute_policy(DisputePolicy::Multiple)
+            .max_tx_per_client(10)
+            .build();
+        assert_eq!(
+            config,
+            EngineConfig {
+                processing_mode: ProcessingMode::Strict,
+                precision: 2,
+                withdrawal_fee: WithdrawalFee::Flat(dec!(0.5)),
+                dispute_policy: DisputePolicy::Multiple,
+                max_tx_per_client: Some(10),
+                ..EngineConfig::default()
+            }
+        );
+        assert_eq!(
+            EngineConfig::builder().strict(true).strict(false).build(),
+            EngineConfig::default()
+        );
+    }
+}
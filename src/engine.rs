@@ -1,200 +1,1572 @@
-use anyhow::{Context, Result};
-use csv::Reader;
+use anyhow::{anyhow, Context, Result};
+use csv::{Reader, ReaderBuilder, StringRecord};
+use log::{info, warn};
 use rust_decimal::Decimal;
-use std::collections::{HashMap, HashSet};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::models::{ClientBalance, OperationRecord, OperationType, TransactionState};
+use crate::amount::parse_amount;
+use crate::checkpoint::SerializedState;
+use crate::client_id::ClientInterner;
+use crate::config::{
+    DisputeHoldPolicy, DisputePolicy, EngineConfig, ExcessPrecision, ProcessingMode, WithdrawalFee,
+};
+use crate::hash_chain::HashChain;
+use crate::invariants::LedgerStats;
+use crate::models::{
+    Amount, ClientBalance, ClientFlows, DisputeShortfall, OperationRecord, OperationType,
+    SkipReason, SkippedRecord, SplitParts, TransactionState, WithdrawalShortfall,
+};
+use crate::stats::AmountSummary;
 
-pub fn process_transactions(
+/// Settings the engine expects of a transaction CSV reader: fields are trimmed and
+/// rows may omit trailing columns (dispute rows carry no amount).
+pub fn reader_builder() -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+    builder.trim(csv::Trim::All).flexible(true);
+    builder
+}
+
+/// Columns every transaction CSV must have, in any order.
+pub const REQUIRED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Columns that may also appear: `target` for `reassign_hold`, `parts` for `split`.
+const OPTIONAL_COLUMNS: [&str; 2] = ["target", "parts"];
+
+/// Fails, naming the offending columns, unless the header has each required column
+/// exactly once and nothing unknown. An empty input passes.
+///
+/// Without this a misnamed column fails every row with a serde error, and a
+/// headerless file has its first data row taken as the header and dropped.
+pub fn check_header<R: std::io::Read>(rdr: &mut Reader<R>) -> Result<()> {
+    let headers = rdr.headers().context("Failed to read input header")?;
+    if headers.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<&str> = headers.iter().collect();
+    let missing: Vec<&str> = REQUIRED_COLUMNS
+        .into_iter()
+        .filter(|column| !names.contains(column))
+        .collect();
+    let unexpected: Vec<&str> = names
+        .iter()
+        .copied()
+        .filter(|name| !REQUIRED_COLUMNS.contains(name) && !OPTIONAL_COLUMNS.contains(name))
+        .collect();
+    let mut seen = HashSet::new();
+    let duplicated: Vec<&str> = names
+        .iter()
+        .copied()
+        .filter(|name| !seen.insert(*name))
+        .collect();
+
+    let mut problems = Vec::new();
+    for (label, columns) in [
+        ("missing", missing),
+        ("unexpected", unexpected),
+        ("duplicate", duplicated),
+    ] {
+        if !columns.is_empty() {
+            problems.push(format!("{} {}", label, columns.join(", ")));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Invalid input header '{}': {} (expected {})",
+        names.join(","),
+        problems.join("; "),
+        REQUIRED_COLUMNS.join(",")
+    ))
+}
+
+/// Final balances and the operations that were skipped, as `process_transactions`
+/// returns them.
+pub type Processed<A = Decimal> = (HashMap<u16, ClientBalance<A>>, Vec<SkippedRecord>);
+
+/// Callback told about each applied operation, the client's balance after it and,
+/// under `string_clients`, the client's name.
+pub type Observer<'a, A = Decimal> =
+    dyn FnMut(&OperationRecord, &ClientBalance<A>, Option<&str>) + 'a;
+
+/// One-shot convenience wrapper around `Engine`, returning the final balances and
+/// the operations that were skipped.
+pub fn process_transactions<A: Amount>(
+    rdr: &mut Reader<impl std::io::Read>,
+    config: &EngineConfig,
+) -> Result<Processed<A>> {
+    let mut engine = Engine::<A>::new(config.clone());
+    engine.process(rdr)?;
+    Ok((engine.client_balances, engine.skipped))
+}
+
+/// As `process_transactions`, calling `observer` with each applied operation and the
+/// client's balance right after it (see `Engine::process_observed`). Skipped
+/// operations are not reported.
+pub fn process_transactions_with_observer<A: Amount>(
     rdr: &mut Reader<impl std::io::Read>,
-) -> Result<HashMap<u16, ClientBalance>> {
-    let mut client_balances: HashMap<u16, ClientBalance> = HashMap::new();
-    let mut transaction_log: HashMap<u32, TransactionState> = HashMap::new();
-    let mut dispute_tracker: HashSet<u32> = HashSet::new();
+    config: &EngineConfig,
+    observer: &mut Observer<'_, A>,
+) -> Result<Processed<A>> {
+    let mut engine = Engine::<A>::new(config.clone());
+    engine.process_observed(rdr, observer)?;
+    Ok((engine.client_balances, engine.skipped))
+}
+
+/// Records handed to a shard thread at a time, so channel traffic stays small next
+/// to the work of applying them.
+const SHARD_BATCH: usize = 1024;
+
+/// As `process_transactions`, applying operations on `num_threads` worker threads.
+///
+/// Rows are parsed on the calling thread and sharded by `client % num_threads`, each
+/// shard running its own `Engine`. Each client's operations keep their input order,
+/// which is all a client's balance depends on as long as no tx id is shared between
+/// clients. The reader tracks which client first referenced each tx id; the first
+/// row referencing one under another client (a reused deposit id, a dispute of
+/// someone else's tx) can only be judged against the whole ledger, so the shards
+/// are drained and merged at that point and the rest of the input is applied
+/// serially. Balances and skip reasons therefore match the serial path; skipped
+/// records come back grouped by shard rather than in input order.
+///
+/// `dispute_window` (which counts txs across the whole run) and `string_clients`
+/// (whose names could not be handed back) are rejected.
+pub fn process_transactions_parallel(
+    rdr: &mut Reader<impl std::io::Read>,
+    config: &EngineConfig,
+    num_threads: usize,
+) -> Result<Processed> {
+    if config.dispute_window.is_some() {
+        return Err(anyhow!(
+            "dispute_window is not supported by process_transactions_parallel"
+        ));
+    }
+    if config.string_clients {
+        return Err(anyhow!(
+            "string_clients is not supported by process_transactions_parallel"
+        ));
+    }
+    let num_threads = num_threads.max(1);
+    // A run-wide hash chain would depend on how shards interleave.
+    let shard_config = EngineConfig {
+        hash_chain: false,
+        ..config.clone()
+    };
+    let mut reader = Engine::new(shard_config.clone());
+
+    thread::scope(|scope| {
+        let (senders, workers): (Vec<_>, Vec<_>) = (0..num_threads)
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel::<Vec<OperationRecord>>(16);
+                let mut shard = Engine::new(shard_config.clone());
+                let worker = scope.spawn(move || -> Result<Engine> {
+                    for batch in receiver {
+                        for record in &batch {
+                            shard.apply(record)?;
+                        }
+                    }
+                    Ok(shard)
+                });
+                (sender, worker)
+            })
+            .unzip();
+        let mut shards = Some(Shards {
+            senders,
+            workers,
+            batches: (0..num_threads).map(|_| Vec::new()).collect(),
+        });
+        // The client that first referenced each tx id.
+        let mut owners: HashMap<u32, u16> = HashMap::new();
+
+        let read = reader.read_records(rdr, &mut |engine, record| {
+            if let Some(active) = &mut shards {
+                if !references_other_client(&mut owners, &record) {
+                    return active.send(record, num_threads);
+                }
+                shards.take().unwrap().merge_into(engine)?;
+                owners = HashMap::new();
+            }
+            engine.apply(&record).map(|_| ())
+        });
+        if let Some(remaining) = shards.take() {
+            remaining.merge_into(&mut reader)?;
+        }
+        read?;
+        Ok((reader.client_balances, reader.skipped))
+    })
+}
+
+/// The worker threads of `process_transactions_parallel` and their pending batches.
+struct Shards<'scope> {
+    senders: Vec<mpsc::SyncSender<Vec<OperationRecord>>>,
+    workers: Vec<thread::ScopedJoinHandle<'scope, Result<Engine>>>,
+    batches: Vec<Vec<OperationRecord>>,
+}
+
+impl Shards<'_> {
+    fn send(&mut self, record: OperationRecord, num_threads: usize) -> Result<()> {
+        let shard = record.client as usize % num_threads;
+        self.batches[shard].push(record);
+        if self.batches[shard].len() == SHARD_BATCH {
+            self.senders[shard]
+                .send(std::mem::take(&mut self.batches[shard]))
+                .map_err(|_| anyhow!("Shard {} stopped", shard))?;
+        }
+        Ok(())
+    }
+
+    /// Flushes every shard, waits for it to finish and folds its state into `engine`.
+    fn merge_into(self, engine: &mut Engine) -> Result<()> {
+        for (sender, batch) in self.senders.into_iter().zip(self.batches) {
+            // A send only fails if the shard already stopped; its error surfaces below.
+            let _ = sender.send(batch);
+        }
+        for worker in self.workers {
+            let shard = worker
+                .join()
+                .map_err(|_| anyhow!("Shard thread panicked"))??;
+            engine.absorb(shard);
+        }
+        Ok(())
+    }
+}
+
+/// Claims every tx id `record` references for its client, returning whether one was
+/// already claimed by a different client.
+fn references_other_client(owners: &mut HashMap<u32, u16>, record: &OperationRecord) -> bool {
+    let parts = record.parts.iter().flat_map(|parts| parts.0.iter());
+    let txs = std::iter::once(record.tx)
+        .chain(record.target)
+        .chain(parts.map(|&(tx, _)| tx));
+    let mut crossed = false;
+    for tx in txs {
+        crossed |= *owners.entry(tx).or_insert(record.client) != record.client;
+    }
+    crossed
+}
+
+/// Processing state that outlives a single reader.
+///
+/// `process_transactions` covers the common case of one input; use an `Engine`
+/// directly when results beyond the balance map (e.g. the hash chain) are needed.
+///
+/// Balances and the transaction log are kept in `A`. Rows are still read as
+/// `Decimal` and converted as they are applied; statistics, hash chains and
+/// shortfalls stay in `Decimal`.
+pub struct Engine<A: Amount = Decimal> {
+    config: EngineConfig,
+    pub client_balances: HashMap<u16, ClientBalance<A>>,
+    /// Client ids in the order they first appeared in the input.
+    pub first_seen: Vec<u16>,
+    transaction_log: HashMap<u32, TransactionState<A>>,
+    /// Under `dispute_window`, the most recently logged tx ids, oldest first.
+    log_order: VecDeque<u32>,
+    /// Open disputes and the amount each one holds.
+    dispute_tracker: HashMap<u32, A>,
+    /// Txs dropped from the log once a resolve or chargeback settled their dispute,
+    /// with their client, so a later dispute by that client can be told apart from
+    /// one on a tx never seen.
+    settled: HashMap<u32, u16>,
+    /// The txs in `settled` that were resolved (under `DisputePolicy::Once`) rather
+    /// than charged back.
+    resolved: HashSet<u32>,
+    /// Under `max_tx_per_client`, operations applied per client so far.
+    applied_per_client: HashMap<u16, u64>,
+    pub hash_chain: Option<HashChain>,
+    /// Under `client_hash`, a chain per client covering only its own operations.
+    pub client_hashes: Option<HashMap<u16, HashChain>>,
+    /// Present under `string_clients`; maps the engine's `u16` ids back to names.
+    pub client_names: Option<ClientInterner>,
+    pub amount_stats: Option<AmountSummary>,
+    pub client_flows: Option<HashMap<u16, ClientFlows>>,
+    pub ledger_stats: Option<HashMap<u16, LedgerStats>>,
+    /// Disputes that held less than their amount under `HoldAvailableOnly`.
+    pub dispute_shortfalls: Vec<DisputeShortfall>,
+    /// Withdrawals filled only in part under `partial_withdrawals`.
+    pub withdrawal_shortfalls: Vec<WithdrawalShortfall>,
+    pub counts: RunCounts,
+    /// Every operation that was not applied, in input order.
+    pub skipped: Vec<SkippedRecord>,
+    /// Called every so many rows read by `process` (`--progress`).
+    pub progress: Option<Progress>,
+}
+
+/// Reports how far `Engine::process` has got: after every `every` rows, `report`
+/// is called with the rows read so far and the time since the `Progress` began.
+pub struct Progress {
+    every: u64,
+    rows: u64,
+    started: Instant,
+    report: Box<dyn FnMut(u64, Duration) + Send>,
+}
+
+impl Progress {
+    pub fn new(every: u64, report: impl FnMut(u64, Duration) + Send + 'static) -> Self {
+        Self {
+            every: every.max(1),
+            rows: 0,
+            started: Instant::now(),
+            report: Box::new(report),
+        }
+    }
+
+    /// Rows counted so far, across every input processed.
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    fn tick(&mut self) {
+        self.rows += 1;
+        if self.rows.is_multiple_of(self.every) {
+            (self.report)(self.rows, self.started.elapsed());
+        }
+    }
+}
+
+/// Outcome of `Engine::validate`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Validation {
+    /// Data rows read, valid or not.
+    pub rows: u64,
+    pub errors: Vec<RowError>,
+}
+
+/// A row that did not parse.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowError {
+    /// 1-based line of the row in its input (the header is line 1).
+    pub line: u64,
+    pub message: String,
+}
+
+/// Running totals over every operation passed to `Engine::apply`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunCounts {
+    pub records_processed: u64,
+    /// Operations that were rejected or had no effect.
+    pub records_skipped: u64,
+    pub disputes_opened: u64,
+}
+
+impl<A: Amount> Engine<A> {
+    pub fn new(config: EngineConfig) -> Self {
+        let hash_chain = config.hash_chain.then(HashChain::new);
+        let client_names = config.string_clients.then(ClientInterner::new);
+        let amount_stats = config.amount_stats.then(AmountSummary::default);
+        let client_flows = config.client_flows.then(HashMap::new);
+        let ledger_stats = config.ledger_stats.then(HashMap::new);
+        let client_hashes = config.client_hash.then(HashMap::new);
+        Self {
+            config,
+            client_balances: HashMap::new(),
+            first_seen: Vec::new(),
+            transaction_log: HashMap::new(),
+            log_order: VecDeque::new(),
+            dispute_tracker: HashMap::new(),
+            settled: HashMap::new(),
+            resolved: HashSet::new(),
+            applied_per_client: HashMap::new(),
+            hash_chain,
+            client_hashes,
+            client_names,
+            amount_stats,
+            client_flows,
+            ledger_stats,
+            dispute_shortfalls: Vec::new(),
+            withdrawal_shortfalls: Vec::new(),
+            counts: RunCounts::default(),
+            skipped: Vec::new(),
+            progress: None,
+        }
+    }
+
+    /// Takes over the state of `shard`, an engine that saw a disjoint set of clients
+    /// and tx ids (see `process_transactions_parallel`).
+    fn absorb(&mut self, shard: Self) {
+        self.client_balances.extend(shard.client_balances);
+        self.first_seen.extend(shard.first_seen);
+        self.transaction_log.extend(shard.transaction_log);
+        self.log_order.extend(shard.log_order);
+        self.dispute_tracker.extend(shard.dispute_tracker);
+        self.settled.extend(shard.settled);
+        self.resolved.extend(shard.resolved);
+        self.applied_per_client.extend(shard.applied_per_client);
+        self.counts.records_processed += shard.counts.records_processed;
+        self.counts.records_skipped += shard.counts.records_skipped;
+        self.counts.disputes_opened += shard.counts.disputes_opened;
+        self.skipped.extend(shard.skipped);
+        self.dispute_shortfalls.extend(shard.dispute_shortfalls);
+        self.withdrawal_shortfalls
+            .extend(shard.withdrawal_shortfalls);
+        if let (Some(hashes), Some(shard_hashes)) = (&mut self.client_hashes, shard.client_hashes) {
+            hashes.extend(shard_hashes);
+        }
+        if let (Some(flows), Some(shard_flows)) = (&mut self.client_flows, shard.client_flows) {
+            flows.extend(shard_flows);
+        }
+        if let (Some(stats), Some(shard_stats)) = (&mut self.ledger_stats, shard.ledger_stats) {
+            stats.extend(shard_stats);
+        }
+    }
+
+    pub fn process(&mut self, rdr: &mut Reader<impl std::io::Read>) -> Result<()> {
+        self.process_observed(rdr, &mut |_, _, _| {})
+    }
+
+    /// As `process`, calling `observer` after every applied operation with the
+    /// record, the client's resulting balance and, under `string_clients`, the
+    /// client's name.
+    pub fn process_observed(
+        &mut self,
+        rdr: &mut Reader<impl std::io::Read>,
+        observer: &mut Observer<'_, A>,
+    ) -> Result<()> {
+        self.read_records(rdr, &mut |engine, record| {
+            if engine.apply(&record)? {
+                let name = engine
+                    .client_names
+                    .as_ref()
+                    .and_then(|names| names.name(record.client));
+                observer(&record, &engine.client_balances[&record.client], name);
+            }
+            Ok(())
+        })?;
+        info!(
+            "processed {} records: {} skipped, {} disputes opened",
+            self.counts.records_processed, self.counts.records_skipped, self.counts.disputes_opened
+        );
+        Ok(())
+    }
+
+    /// Reads every row, handing each to `sink` once resolved to the engine's
+    /// `u16`/`Decimal` client and amount.
+    fn read_records(
+        &mut self,
+        rdr: &mut Reader<impl std::io::Read>,
+        sink: &mut dyn FnMut(&mut Self, OperationRecord) -> Result<()>,
+    ) -> Result<()> {
+        check_header(rdr)?;
+        match (
+            self.client_names.is_some(),
+            self.config.custom_amount_syntax(),
+        ) {
+            (false, false) => self.read_as::<u16, Decimal>(rdr, sink),
+            (false, true) => self.read_as::<u16, String>(rdr, sink),
+            (true, false) => self.read_as::<String, Decimal>(rdr, sink),
+            (true, true) => self.read_as::<String, String>(rdr, sink),
+        }
+    }
+
+    /// Deserializes rows with the client/amount column types the configuration
+    /// calls for.
+    fn read_as<C, F>(
+        &mut self,
+        rdr: &mut Reader<impl std::io::Read>,
+        sink: &mut dyn FnMut(&mut Self, OperationRecord) -> Result<()>,
+    ) -> Result<()>
+    where
+        C: ClientField,
+        F: AmountField,
+    {
+        let headers = rdr
+            .headers()
+            .context("Failed to read input header")?
+            .clone();
+        let mut row = StringRecord::new();
+        while rdr
+            .read_record(&mut row)
+            .context("Failed to deserialize record")?
+        {
+            let record: OperationRecord<C, F> = row
+                .deserialize(Some(&headers))
+                .context("Failed to deserialize record")?;
+            let record = self.resolve(record)?;
+            self.check_row_length(&headers, &row, &record)?;
+            sink(self, record)?;
+            if let Some(progress) = &mut self.progress {
+                progress.tick();
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses one row read outside `process` (e.g. off a socket) against `headers`,
+    /// with the same column handling `process` applies.
+    pub fn parse_row(
+        &mut self,
+        headers: &StringRecord,
+        row: &StringRecord,
+    ) -> Result<OperationRecord> {
+        match (
+            self.client_names.is_some(),
+            self.config.custom_amount_syntax(),
+        ) {
+            (false, false) => self.parse_as::<u16, Decimal>(headers, row),
+            (false, true) => self.parse_as::<u16, String>(headers, row),
+            (true, false) => self.parse_as::<String, Decimal>(headers, row),
+            (true, true) => self.parse_as::<String, String>(headers, row),
+        }
+    }
+
+    fn parse_as<C, F>(
+        &mut self,
+        headers: &StringRecord,
+        row: &StringRecord,
+    ) -> Result<OperationRecord>
+    where
+        C: ClientField,
+        F: AmountField,
+    {
+        let record: OperationRecord<C, F> = row
+            .deserialize(Some(headers))
+            .context("Failed to deserialize record")?;
+        let record = self.resolve(record)?;
+        self.check_row_length(headers, row, &record)?;
+        Ok(record)
+    }
+
+    /// Under `ProcessingMode::Strict`, fails a row with more fields than `headers`,
+    /// or with fewer where a dropped column is one its type needs, reporting it as
+    /// `MalformedRow`. Otherwise ragged rows are read as they are: missing trailing
+    /// fields are empty and extra ones are ignored.
+    fn check_row_length(
+        &mut self,
+        headers: &StringRecord,
+        row: &StringRecord,
+        record: &OperationRecord,
+    ) -> Result<()> {
+        if self.config.processing_mode != ProcessingMode::Strict {
+            return Ok(());
+        }
+        let (expected, actual) = (headers.len(), row.len());
+        let fits = actual <= expected
+            && headers
+                .iter()
+                .skip(actual)
+                .all(|column| !column_needed(column, &record.r#type));
+        if fits {
+            return Ok(());
+        }
+        self.counts.records_processed += 1;
+        self.skip(record, SkipReason::MalformedRow { expected, actual })
+            .map(|_| ())
+    }
+
+    /// Converts a row's client and amount columns to the engine's `u16`/`Decimal`.
+    fn resolve<C, F>(&mut self, record: OperationRecord<C, F>) -> Result<OperationRecord>
+    where
+        C: ClientField,
+        F: AmountField,
+    {
+        let amount = match record.amount {
+            Some(raw) => raw
+                .into_amount(&self.config)
+                .with_context(|| format!("Failed to parse amount for tx {}", record.tx))?,
+            None => None,
+        };
+        Ok(OperationRecord {
+            r#type: record.r#type,
+            client: record.client.into_client_id(self.client_names.as_mut())?,
+            tx: record.tx,
+            amount,
+            target: record.target,
+            parts: record.parts,
+        })
+    }
+
+    /// Parses every row as `process` would, without applying any, and reports the
+    /// rows that fail instead of stopping at the first. Balances are left untouched.
+    /// A bad header is reported as an error on line 1, with no rows read.
+    pub fn validate(&mut self, rdr: &mut Reader<impl std::io::Read>) -> Result<Validation> {
+        if let Err(err) = check_header(rdr) {
+            return Ok(Validation {
+                rows: 0,
+                errors: vec![RowError {
+                    line: 1,
+                    message: err.to_string(),
+                }],
+            });
+        }
+        match (
+            self.client_names.is_some(),
+            self.config.custom_amount_syntax(),
+        ) {
+            (false, false) => self.validate_as::<u16, Decimal>(rdr),
+            (false, true) => self.validate_as::<u16, String>(rdr),
+            (true, false) => self.validate_as::<String, Decimal>(rdr),
+            (true, true) => self.validate_as::<String, String>(rdr),
+        }
+    }
+
+    fn validate_as<C, F>(&mut self, rdr: &mut Reader<impl std::io::Read>) -> Result<Validation>
+    where
+        C: ClientField,
+        F: AmountField,
+    {
+        let headers = rdr
+            .headers()
+            .context("Failed to read input header")?
+            .clone();
+        let mut validation = Validation::default();
+        let mut row = StringRecord::new();
+        loop {
+            match rdr.read_record(&mut row) {
+                Ok(false) => break,
+                Ok(true) => {}
+                Err(err) if !err.is_io_error() => {
+                    validation.rows += 1;
+                    validation.errors.push(RowError {
+                        line: err.position().map_or(0, |pos| pos.line()),
+                        message: err.to_string(),
+                    });
+                    continue;
+                }
+                Err(err) => return Err(err).context("Failed to read input"),
+            }
+            validation.rows += 1;
+            let parsed = row
+                .deserialize::<OperationRecord<C, F>>(Some(&headers))
+                .map_err(anyhow::Error::from)
+                .and_then(|record| self.resolve(record));
+            if let Err(err) = parsed {
+                validation.errors.push(RowError {
+                    line: row.position().map_or(0, |pos| pos.line()),
+                    message: format!("{:#}", err),
+                });
+            }
+        }
+        Ok(validation)
+    }
+
+    /// Resolves every dispute still open, in tx order, returning how many were
+    /// resolved.
+    ///
+    /// Each one goes through `apply` as a regular `resolve`, so it is hashed and
+    /// logged like any other operation, and can be skipped like one (e.g. under
+    /// `max_tx_per_client`); a skipped resolve leaves its dispute open.
+    pub fn resolve_open_disputes(&mut self) -> Result<usize> {
+        let mut open: Vec<u32> = self.dispute_tracker.keys().copied().collect();
+        open.sort_unstable();
+        let mut resolved = 0;
+        for tx in open {
+            let client = self.transaction_log[&tx].client;
+            let applied = self.apply(&OperationRecord {
+                r#type: OperationType::Resolve,
+                client,
+                tx,
+                amount: None,
+                target: None,
+                parts: None,
+            })?;
+            if applied {
+                resolved += 1;
+            }
+        }
+        Ok(resolved)
+    }
 
-    for result in rdr.deserialize() {
-        let record: OperationRecord = result.context("Failed to deserialize record")?;
+    /// Applies one operation, returning whether it changed any state. Under
+    /// `ProcessingMode::Strict` an operation that would be skipped is an error instead.
+    pub fn apply(&mut self, record: &OperationRecord) -> Result<bool> {
+        if let Some(clients) = &self.config.clients {
+            if !clients.contains(&record.client) {
+                return Ok(false);
+            }
+        }
+        let rounded;
+        let record = match (&record.r#type, record.amount) {
+            (OperationType::Deposit | OperationType::Withdrawal, Some(amount))
+                if self.config.excess_precision == ExcessPrecision::Round
+                    && validate_amount_scale(amount, self.config.precision).is_err() =>
+            {
+                rounded = OperationRecord {
+                    amount: Some(amount.round_dp(self.config.precision)),
+                    ..record.clone()
+                };
+                &rounded
+            }
+            _ => record,
+        };
+        if let (Some(stats), Some(amount)) = (&mut self.amount_stats, record.amount) {
+            match record.r#type {
+                OperationType::Deposit => stats.deposits.record(amount),
+                OperationType::Withdrawal => stats.withdrawals.record(amount),
+                _ => {}
+            }
+        }
+        // Checked before the client gets a balance, so no phantom client 0 appears.
+        // Under `string_clients` id 0 is just the first name seen, not a reserved id.
+        let reserved_client = record.client == 0 && self.client_names.is_none();
+        if self.config.reject_zero_ids && (reserved_client || record.tx == 0) {
+            self.counts.records_processed += 1;
+            return self.skip(record, SkipReason::ReservedId);
+        }
+        // Only money arriving (or a deliberate freeze) opens an account; anything
+        // else for a client never seen would otherwise list a phantom zero balance.
+        let opens_account = matches!(
+            record.r#type,
+            OperationType::Deposit | OperationType::Bonus | OperationType::Freeze
+        );
+        if !opens_account && !self.client_balances.contains_key(&record.client) {
+            self.counts.records_processed += 1;
+            return self.skip(record, SkipReason::UnknownClient);
+        }
+        // What a resolve or chargeback releases, and whether from a deposit, for
+        // `ledger_stats` (both are gone from the engine once it settles).
+        let released = self.ledger_stats.as_ref().and_then(|_| {
+            let held = self.dispute_tracker.get(&record.tx)?.to_decimal();
+            Some((held, self.transaction_log.get(&record.tx)?.is_deposit))
+        });
+        let balance = match self.client_balances.entry(record.client) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                self.first_seen.push(record.client);
+                entry.insert(ClientBalance::default())
+            }
+        };
+        let applied = self.applied_per_client.get(&record.client).copied();
+        let rate_limited = self
+            .config
+            .max_tx_per_client
+            .is_some_and(|max| applied.unwrap_or(0) >= max);
+        let transaction_log = &mut self.transaction_log;
+        let dispute_tracker = &mut self.dispute_tracker;
+        let settled = &mut self.settled;
+        let resolved = &mut self.resolved;
 
-        let balance = client_balances
-            .entry(record.client)
-            .or_insert_with(ClientBalance::new);
+        let outcome = if rate_limited {
+            Err(SkipReason::ClientRateLimited)
+        } else {
+            check_amount_presence(record, &self.config)
+                .and_then(|()| check_amount_scale(record, &self.config))
+                .and_then(|()| to_amount::<A>(record.amount))
+                .and_then(|amount| match record.r#type {
+                    OperationType::Deposit => {
+                        apply_deposit(transaction_log, balance, record.tx, record.client, amount)
+                    }
+                    OperationType::Withdrawal => apply_withdrawal(
+                        balance,
+                        record.tx,
+                        record.client,
+                        amount,
+                        transaction_log,
+                        &self.config,
+                    ),
+                    OperationType::Dispute => apply_dispute(
+                        balance,
+                        record.tx,
+                        record.client,
+                        amount,
+                        transaction_log,
+                        dispute_tracker,
+                        &self.config,
+                    )
+                    .map_err(|reason| match reason {
+                        SkipReason::UnknownTx
+                            if settled.get(&record.tx) == Some(&record.client) =>
+                        {
+                            if resolved.contains(&record.tx) {
+                                SkipReason::DisputeAlreadyUsed
+                            } else {
+                                SkipReason::AlreadySettled
+                            }
+                        }
+                        reason => reason,
+                    }),
+                    OperationType::Resolve => {
+                        let outcome = apply_resolve(
+                            balance,
+                            record.tx,
+                            record.client,
+                            transaction_log,
+                            dispute_tracker,
+                        );
+                        if self.config.dispute_policy == DisputePolicy::Once {
+                            cleanup_transaction(transaction_log, dispute_tracker, record.tx);
+                            if outcome.is_ok() {
+                                settled.insert(record.tx, record.client);
+                                resolved.insert(record.tx);
+                            }
+                        }
+                        outcome
+                    }
+                    OperationType::Chargeback => {
+                        let outcome = apply_chargeback(
+                            balance,
+                            record.tx,
+                            record.client,
+                            transaction_log,
+                            dispute_tracker,
+                        );
+                        cleanup_transaction(transaction_log, dispute_tracker, record.tx);
+                        if outcome.is_ok() {
+                            settled.insert(record.tx, record.client);
+                        }
+                        outcome
+                    }
+                    OperationType::ReassignHold => apply_reassign_hold(
+                        balance,
+                        record.tx,
+                        record.target,
+                        record.client,
+                        transaction_log,
+                        dispute_tracker,
+                        &self.config,
+                    ),
+                    OperationType::Bonus => apply_bonus(balance, amount),
+                    OperationType::Split => apply_split(
+                        record.tx,
+                        record.client,
+                        record.parts.as_ref(),
+                        transaction_log,
+                        dispute_tracker,
+                    ),
+                    OperationType::Freeze => {
+                        balance.locked = true;
+                        Ok(())
+                    }
+                    OperationType::Unfreeze => {
+                        balance.locked = false;
+                        balance.lock_reason = None;
+                        Ok(())
+                    }
+                })
+        };
 
+        self.counts.records_processed += 1;
+        if let Err(reason) = outcome {
+            return self.skip(record, reason);
+        }
+        // A partly filled withdrawal is accounted below at what it actually took.
+        let filled;
+        let record = match (&record.r#type, record.amount) {
+            (OperationType::Withdrawal, Some(requested)) if self.config.partial_withdrawals => {
+                let taken = self.transaction_log[&record.tx].amount.to_decimal();
+                if taken < requested {
+                    self.withdrawal_shortfalls.push(WithdrawalShortfall {
+                        client: record.client,
+                        tx: record.tx,
+                        shortfall: requested - taken,
+                    });
+                }
+                filled = OperationRecord {
+                    amount: Some(taken),
+                    ..record.clone()
+                };
+                &filled
+            }
+            _ => record,
+        };
+        if self.config.max_tx_per_client.is_some() {
+            *self.applied_per_client.entry(record.client).or_default() += 1;
+        }
         match record.r#type {
-            OperationType::Deposit => apply_deposit(
-                &mut transaction_log,
-                balance,
-                record.tx,
-                record.client,
-                record.amount,
-            ),
-            OperationType::Withdrawal => apply_withdrawal(
-                balance,
-                record.tx,
-                record.client,
-                record.amount,
-                &mut transaction_log,
-            ),
-            OperationType::Dispute => apply_dispute(
-                balance,
+            OperationType::Dispute => self.counts.disputes_opened += 1,
+            OperationType::Chargeback => {
+                info!(
+                    "chargeback on tx {} locked client {}",
+                    record.tx, record.client
+                );
+            }
+            _ => {}
+        }
+        if let Some(window) = self.config.dispute_window {
+            self.retain_window(record, window);
+        }
+        let held_tx = match record.r#type {
+            OperationType::Dispute => Some(record.tx),
+            OperationType::ReassignHold => record.target,
+            _ => None,
+        };
+        if let Some(tx) = held_tx {
+            // A dispute owes its own amount if it gave one (as under
+            // `partial_disputes`), otherwise the whole tx.
+            let owed = match record.r#type {
+                OperationType::Dispute => record.amount,
+                _ => None,
+            };
+            let owed = owed.unwrap_or(self.transaction_log[&tx].amount.to_decimal());
+            let shortfall = owed - self.dispute_tracker[&tx].to_decimal();
+            if shortfall > Decimal::ZERO {
+                self.dispute_shortfalls.push(DisputeShortfall {
+                    client: record.client,
+                    tx,
+                    shortfall,
+                });
+            }
+        }
+        if let Some(stats) = &mut self.ledger_stats {
+            let stats = stats.entry(record.client).or_default();
+            let amount = record.amount.unwrap_or_default();
+            match (&record.r#type, released) {
+                (OperationType::Deposit | OperationType::Bonus, _) => stats.credited += amount,
+                (OperationType::Withdrawal, _) => {
+                    let fee = self.config.withdrawal_fee.on(amount, self.config.precision);
+                    stats.debited += amount + fee.unwrap_or_default();
+                }
+                (OperationType::Dispute, _) if !self.transaction_log[&record.tx].is_deposit => {
+                    stats.withdrawal_holds += self.dispute_tracker[&record.tx].to_decimal();
+                }
+                (OperationType::Resolve, Some((held, false))) => stats.withdrawal_holds -= held,
+                (OperationType::Chargeback, Some((held, true))) => stats.charged_back += held,
+                (OperationType::Chargeback, Some((held, false))) => {
+                    stats.withdrawal_holds -= held;
+                    stats.reversed += held;
+                }
+                _ => {}
+            }
+        }
+        if let Some(chain) = &mut self.hash_chain {
+            chain.append(record);
+        }
+        if let Some(chains) = &mut self.client_hashes {
+            chains
+                .entry(record.client)
+                .or_insert_with(HashChain::new)
+                .append(record);
+        }
+        if let (Some(flows), Some(amount)) = (&mut self.client_flows, record.amount) {
+            let flows = flows.entry(record.client).or_default();
+            match record.r#type {
+                OperationType::Deposit => {
+                    flows.deposited += amount;
+                    flows.deposit_count += 1;
+                }
+                OperationType::Withdrawal => {
+                    flows.withdrawn += amount;
+                    flows.withdrawal_count += 1;
+                    let fee = self.config.withdrawal_fee.on(amount, self.config.precision);
+                    flows.fees += fee.unwrap_or_default();
+                }
+                _ => {}
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reports `record` as not applied, failing under `ProcessingMode::Strict`.
+    fn skip(&mut self, record: &OperationRecord, reason: SkipReason) -> Result<bool> {
+        warn!(
+            "skipped {:?} tx {} for client {}: {:?}",
+            record.r#type, record.tx, record.client, reason
+        );
+        self.counts.records_skipped += 1;
+        self.skipped.push(SkippedRecord {
+            tx: record.tx,
+            client: record.client,
+            r#type: record.r#type.clone(),
+            reason,
+        });
+        if self.config.processing_mode == ProcessingMode::Strict {
+            return Err(anyhow!(
+                "{:?} tx {} for client {} rejected: {:?}",
+                record.r#type,
                 record.tx,
                 record.client,
-                &transaction_log,
-                &mut dispute_tracker,
-            ),
-            OperationType::Resolve => {
-                apply_resolve(
-                    balance,
-                    record.tx,
-                    record.client,
-                    &transaction_log,
-                    &mut dispute_tracker,
-                )?;
-                cleanup_transaction(&mut transaction_log, &dispute_tracker, record.tx);
+                reason
+            ));
+        }
+        Ok(false)
+    }
+
+    /// Records the txs `record` logged and evicts the oldest beyond `window`. An
+    /// evicted tx under an open dispute stays in the log until the dispute settles.
+    fn retain_window(&mut self, record: &OperationRecord, window: usize) {
+        match (&record.r#type, &record.parts) {
+            (OperationType::Deposit | OperationType::Withdrawal, _) => {
+                self.log_order.push_back(record.tx)
             }
-            OperationType::Chargeback => {
-                apply_chargeback(
-                    balance,
-                    record.tx,
-                    record.client,
-                    &transaction_log,
-                    &mut dispute_tracker,
-                )?;
-                cleanup_transaction(&mut transaction_log, &dispute_tracker, record.tx);
+            (OperationType::Split, Some(SplitParts(parts))) => {
+                self.log_order.extend(parts.iter().map(|&(tx, _)| tx))
             }
-        };
+            _ => return,
+        }
+        while self.log_order.len() > window {
+            let Some(tx) = self.log_order.pop_front() else {
+                break;
+            };
+            if !self.dispute_tracker.contains_key(&tx) {
+                self.transaction_log.remove(&tx);
+            }
+        }
+    }
+}
+
+/// Checkpoints hold `Decimal` amounts, so only a `Decimal` engine can take or
+/// resume from one.
+impl Engine {
+    /// Captures the ledger so a run can stop here and `restore` later. The
+    /// `skipped`, `dispute_shortfalls` and `withdrawal_shortfalls` lists are not
+    /// included.
+    pub fn snapshot(&self) -> SerializedState {
+        SerializedState {
+            client_balances: self
+                .client_balances
+                .iter()
+                .map(|(&client, balance)| (client, balance.into()))
+                .collect(),
+            first_seen: self.first_seen.clone(),
+            transaction_log: self.transaction_log.clone(),
+            log_order: self.log_order.clone(),
+            dispute_tracker: self.dispute_tracker.clone(),
+            settled: self.settled.clone(),
+            resolved: self.resolved.clone(),
+            applied_per_client: self.applied_per_client.clone(),
+            counts: self.counts,
+        }
     }
 
-    Ok(client_balances)
+    /// An engine that continues from `state` as if it had processed the same rows.
+    ///
+    /// A snapshot carries no hash chains, client names or statistics, so `config`
+    /// must leave those off.
+    pub fn restore(config: EngineConfig, state: SerializedState) -> Result<Self> {
+        let unsupported = [
+            (config.hash_chain, "hash_chain"),
+            (config.client_hash, "client_hash"),
+            (config.string_clients, "string_clients"),
+            (config.amount_stats, "amount_stats"),
+            (config.client_flows, "client_flows"),
+            (config.ledger_stats, "ledger_stats"),
+        ];
+        if let Some((_, name)) = unsupported.iter().find(|(enabled, _)| *enabled) {
+            return Err(anyhow!("Cannot restore a checkpoint with {} enabled", name));
+        }
+        Ok(Self {
+            client_balances: state
+                .client_balances
+                .into_iter()
+                .map(|(client, balance)| (client, balance.into()))
+                .collect(),
+            first_seen: state.first_seen,
+            transaction_log: state.transaction_log,
+            log_order: state.log_order,
+            dispute_tracker: state.dispute_tracker,
+            settled: state.settled,
+            resolved: state.resolved,
+            applied_per_client: state.applied_per_client,
+            counts: state.counts,
+            ..Self::new(config)
+        })
+    }
 }
 
-fn apply_deposit(
-    transaction_log: &mut HashMap<u32, TransactionState>,
-    balance: &mut ClientBalance,
+fn apply_deposit<A: Amount>(
+    transaction_log: &mut HashMap<u32, TransactionState<A>>,
+    balance: &mut ClientBalance<A>,
     tx: u32,
     client: u16,
-    amount: Option<Decimal>,
-) {
-    if let Some(amt) = amount {
-        if amt > Decimal::ZERO && !balance.locked && !transaction_log.contains_key(&tx) {
-            balance.available += amt;
-            transaction_log.insert(
-                tx,
-                TransactionState {
-                    client,
-                    amount: amt,
-                    is_deposit: true,
-                },
-            );
+    amount: Option<A>,
+) -> Result<(), SkipReason> {
+    let amt = positive_amount(amount)?;
+    if balance.locked {
+        return Err(SkipReason::AccountLocked);
+    }
+    if let Some(first) = transaction_log.get(&tx) {
+        return Err(SkipReason::DuplicateTx {
+            owner: first.client,
+            client,
+        });
+    }
+    let available = checked(balance.available.checked_add(amt))?;
+    set_balance(balance, available, balance.held)?;
+    transaction_log.insert(
+        tx,
+        TransactionState {
+            client,
+            amount: amt,
+            is_deposit: true,
+        },
+    );
+    Ok(())
+}
+
+/// Whether a row of type `kind` must reach `column`, rather than end before it.
+fn column_needed(column: &str, kind: &OperationType) -> bool {
+    match column {
+        "amount" => matches!(
+            kind,
+            OperationType::Deposit | OperationType::Withdrawal | OperationType::Bonus
+        ),
+        "target" => *kind == OperationType::ReassignHold,
+        "parts" => *kind == OperationType::Split,
+        _ => true,
+    }
+}
+
+/// Rejects a row whose `amount` column is missing where its type needs one, or
+/// present where it is meaningless. Dispute amounts are expected under
+/// `match_dispute_amount`; `reassign_hold` and `split` are checked when applied.
+fn check_amount_presence(
+    record: &OperationRecord,
+    config: &EngineConfig,
+) -> Result<(), SkipReason> {
+    let present = record.amount.is_some();
+    match record.r#type {
+        OperationType::Deposit | OperationType::Withdrawal | OperationType::Bonus if !present => {
+            Err(SkipReason::MalformedRecord("missing amount"))
+        }
+        OperationType::Dispute
+            if present && !config.match_dispute_amount && !config.partial_disputes =>
+        {
+            Err(SkipReason::MalformedRecord("unexpected amount on dispute"))
+        }
+        OperationType::Resolve | OperationType::Chargeback if present => {
+            Err(SkipReason::MalformedRecord("unexpected amount"))
         }
+        _ => Ok(()),
     }
 }
 
-fn apply_withdrawal(
-    balance: &mut ClientBalance,
-    tx: u32,
-    client: u16,
-    amount: Option<Decimal>,
-    transaction_log: &mut HashMap<u32, TransactionState>,
-) {
-    if let Some(amt) = amount {
-        if amt > Decimal::ZERO
-            && !balance.locked
-            && balance.available >= amt
-            && !transaction_log.contains_key(&tx)
+/// Under `ExcessPrecision::Reject`, fails a deposit or withdrawal whose amount has
+/// more decimal places than `config.precision`.
+fn check_amount_scale(record: &OperationRecord, config: &EngineConfig) -> Result<(), SkipReason> {
+    match (&record.r#type, record.amount) {
+        (OperationType::Deposit | OperationType::Withdrawal, Some(amount))
+            if config.excess_precision == ExcessPrecision::Reject =>
         {
-            balance.available -= amt;
-            transaction_log.insert(
-                tx,
-                TransactionState {
-                    client,
-                    amount: amt,
-                    is_deposit: false,
-                },
-            );
+            validate_amount_scale(amount, config.precision)
         }
+        _ => Ok(()),
+    }
+}
+
+/// Fails if `amount` needs more than `max_scale` decimal places. Trailing zeros do
+/// not count, so `1.50000` fits in 4.
+fn validate_amount_scale(amount: Decimal, max_scale: u32) -> Result<(), SkipReason> {
+    if amount.normalize().scale() > max_scale {
+        Err(SkipReason::ExcessivePrecision)
+    } else {
+        Ok(())
+    }
+}
+
+/// `amount` in the engine's amount type; `ExcessivePrecision` if `A` cannot hold it.
+fn to_amount<A: Amount>(amount: Option<Decimal>) -> Result<Option<A>, SkipReason> {
+    amount
+        .map(|amount| A::from_decimal(amount).ok_or(SkipReason::ExcessivePrecision))
+        .transpose()
+}
+
+fn positive_amount<A: Amount>(amount: Option<A>) -> Result<A, SkipReason> {
+    amount
+        .filter(|amt| *amt > A::ZERO)
+        .ok_or(SkipReason::NonPositiveAmount)
+}
+
+/// The result of a `checked_*` operation, or `ArithmeticOverflow` if it overflowed.
+fn checked<A>(value: Option<A>) -> Result<A, SkipReason> {
+    value.ok_or(SkipReason::ArithmeticOverflow)
+}
+
+/// Stores new `available`/`held` figures, refusing any pair whose total (written
+/// to the output) would itself overflow. Nothing changes on error.
+fn set_balance<A: Amount>(
+    balance: &mut ClientBalance<A>,
+    available: A,
+    held: A,
+) -> Result<(), SkipReason> {
+    checked(available.checked_add(held))?;
+    balance.available = available;
+    balance.held = held;
+    Ok(())
+}
+
+/// Credits a promotional amount. Unlike a deposit it is not recorded in the
+/// transaction log, so no later dispute can reverse it (and its tx id is not checked
+/// for duplicates).
+fn apply_bonus<A: Amount>(
+    balance: &mut ClientBalance<A>,
+    amount: Option<A>,
+) -> Result<(), SkipReason> {
+    let amt = positive_amount(amount)?;
+    if balance.locked {
+        return Err(SkipReason::AccountLocked);
     }
+    let available = checked(balance.available.checked_add(amt))?;
+    set_balance(balance, available, balance.held)
 }
 
-fn apply_dispute(
-    balance: &mut ClientBalance,
+fn apply_withdrawal<A: Amount>(
+    balance: &mut ClientBalance<A>,
     tx: u32,
     client: u16,
-    transaction_log: &HashMap<u32, TransactionState>,
-    dispute_tracker: &mut HashSet<u32>,
-) {
-    if let Some(state) = transaction_log.get(&tx) {
-        if state.client == client && state.is_deposit && dispute_tracker.insert(tx) {
-            let amt = state.amount;
-            balance.available -= amt;
-            balance.held += amt;
+    amount: Option<A>,
+    transaction_log: &mut HashMap<u32, TransactionState<A>>,
+    config: &EngineConfig,
+) -> Result<(), SkipReason> {
+    let amt = positive_amount(amount)?;
+    if balance.locked {
+        return Err(SkipReason::AccountLocked);
+    }
+    if let Some(first) = transaction_log.get(&tx) {
+        return Err(SkipReason::DuplicateTx {
+            owner: first.client,
+            client,
+        });
+    }
+    if config
+        .max_withdrawal
+        .is_some_and(|max| amt.to_decimal() > max)
+    {
+        return Err(SkipReason::ExceedsWithdrawalLimit);
+    }
+    // Held funds are already excluded from `available`; some institutions still let
+    // clients draw against them.
+    let withdrawable = if config.include_held_in_withdrawable {
+        checked(balance.available.checked_add(balance.held))?
+    } else {
+        // `held` only moves with disputes, so it is positive while one holds funds.
+        // Money deposited since could otherwise be withdrawn ahead of the chargeback
+        // that needs it, so nothing leaves until the client's disputes settle.
+        if balance.held > A::ZERO {
+            return Err(SkipReason::OpenDispute);
         }
+        balance.available
+    };
+    let fee = config
+        .withdrawal_fee
+        .on(amt.to_decimal(), config.precision)
+        .and_then(A::from_decimal);
+    let mut amt = amt;
+    let mut debit = checked(amt.checked_add(checked(fee)?))?;
+    let grace = checked(A::from_decimal(config.withdrawal_grace))?;
+    let limit = checked(withdrawable.checked_add(grace))?;
+    if limit < debit {
+        // Without a fee, what can be taken is simply the limit.
+        let partial = config.partial_withdrawals && config.withdrawal_fee == WithdrawalFee::None;
+        if !partial || limit <= A::ZERO {
+            return Err(SkipReason::InsufficientFunds);
+        }
+        amt = limit;
+        debit = limit;
     }
+    let available = checked(balance.available.checked_sub(debit))?;
+    set_balance(balance, available, balance.held)?;
+    transaction_log.insert(
+        tx,
+        TransactionState {
+            client,
+            amount: amt,
+            is_deposit: false,
+        },
+    );
+    Ok(())
 }
 
-fn apply_resolve(
-    balance: &mut ClientBalance,
+fn apply_dispute<A: Amount>(
+    balance: &mut ClientBalance<A>,
     tx: u32,
     client: u16,
-    transaction_log: &HashMap<u32, TransactionState>,
-    dispute_tracker: &mut HashSet<u32>,
-) -> Result<()> {
-    if let Some(state) = transaction_log.get(&tx) {
-        if state.client == client && dispute_tracker.remove(&tx) {
-            let amt = state.amount;
-            balance.available += amt;
-            balance.held -= amt;
-        }
+    amount: Option<A>,
+    transaction_log: &HashMap<u32, TransactionState<A>>,
+    dispute_tracker: &mut HashMap<u32, A>,
+    config: &EngineConfig,
+) -> Result<(), SkipReason> {
+    let state = logged_for(transaction_log, tx, client)?;
+    if dispute_tracker.contains_key(&tx) {
+        return Err(SkipReason::AlreadyDisputed);
+    }
+    if config.deposit_disputes_only && !state.is_deposit {
+        return Err(SkipReason::DisputeOnNonDeposit);
+    }
+    // Strict feeds echo the disputed amount; anything else is an inconsistency.
+    if config.match_dispute_amount && amount != Some(state.amount) {
+        return Err(SkipReason::DisputeAmountMismatch);
+    }
+    let disputed = match amount.filter(|_| config.partial_disputes) {
+        Some(part) if part > state.amount => return Err(SkipReason::DisputeAmountMismatch),
+        // Neither "the whole tx" nor "nothing" is a safe reading of a zero.
+        Some(part) if part == A::ZERO => return Err(SkipReason::ZeroDisputeAmount),
+        Some(part) => positive_amount(Some(part))?,
+        None => state.amount,
+    };
+    // A disputed withdrawal already left `available`, so the hold policies (which
+    // are about not overdrawing it) only apply to deposits.
+    let amt = if state.is_deposit {
+        hold_amount(balance.available, disputed, config).ok_or(SkipReason::InsufficientFunds)?
+    } else {
+        disputed
+    };
+    let available = if state.is_deposit {
+        checked(balance.available.checked_sub(amt))?
+    } else {
+        balance.available
+    };
+    let held = checked(balance.held.checked_add(amt))?;
+    // `held` only ever moves with disputes, so it is the client's disputed total.
+    let within_ceiling = config
+        .max_disputed_amount_per_client
+        .is_none_or(|ceiling| held.to_decimal() <= ceiling);
+    if !within_ceiling {
+        return Err(SkipReason::DisputeAmountCeiling);
     }
+    set_balance(balance, available, held)?;
+    dispute_tracker.insert(tx, amt);
     Ok(())
 }
 
-fn apply_chargeback(
-    balance: &mut ClientBalance,
+/// The logged transaction `tx`, provided it belongs to `client`.
+fn logged_for<A>(
+    transaction_log: &HashMap<u32, TransactionState<A>>,
     tx: u32,
     client: u16,
-    transaction_log: &HashMap<u32, TransactionState>,
-    dispute_tracker: &mut HashSet<u32>,
-) -> Result<()> {
-    if let Some(state) = transaction_log.get(&tx) {
-        if state.client == client && dispute_tracker.remove(&tx) {
-            let amt = state.amount;
-            balance.held -= amt;
-            balance.locked = true;
-        }
+) -> Result<&TransactionState<A>, SkipReason> {
+    let state = transaction_log.get(&tx).ok_or(SkipReason::UnknownTx)?;
+    if state.client != client {
+        return Err(SkipReason::ClientMismatch {
+            expected: state.client,
+            actual: client,
+        });
+    }
+    Ok(state)
+}
+
+/// How much of a disputed `amount` to hold given the client's current `available`,
+/// per `config.dispute_hold_policy`. `None` means the dispute is rejected.
+fn hold_amount<A: Amount>(available: A, amount: A, config: &EngineConfig) -> Option<A> {
+    match config.dispute_hold_policy {
+        DisputeHoldPolicy::HoldFull => Some(amount),
+        DisputeHoldPolicy::HoldAvailableOnly => Some(amount.min(available.max(A::ZERO))),
+        DisputeHoldPolicy::RejectOverdispute => (available >= amount).then_some(amount),
     }
+}
+
+fn apply_resolve<A: Amount>(
+    balance: &mut ClientBalance<A>,
+    tx: u32,
+    client: u16,
+    transaction_log: &HashMap<u32, TransactionState<A>>,
+    dispute_tracker: &mut HashMap<u32, A>,
+) -> Result<(), SkipReason> {
+    let state = logged_for(transaction_log, tx, client)?;
+    release_hold(balance, tx, dispute_tracker, |balance, amt| {
+        let available = if state.is_deposit {
+            checked(balance.available.checked_add(amt))?
+        } else {
+            balance.available
+        };
+        Ok((available, checked(balance.held.checked_sub(amt))?))
+    })
+}
+
+fn apply_chargeback<A: Amount>(
+    balance: &mut ClientBalance<A>,
+    tx: u32,
+    client: u16,
+    transaction_log: &HashMap<u32, TransactionState<A>>,
+    dispute_tracker: &mut HashMap<u32, A>,
+) -> Result<(), SkipReason> {
+    let state = logged_for(transaction_log, tx, client)?;
+    release_hold(balance, tx, dispute_tracker, |balance, amt| {
+        let available = if state.is_deposit {
+            balance.available
+        } else {
+            checked(balance.available.checked_add(amt))?
+        };
+        Ok((available, checked(balance.held.checked_sub(amt))?))
+    })?;
+    balance.locked = true;
+    // A chargeback on an already locked account leaves the original reason.
+    balance.lock_reason.get_or_insert(tx);
     Ok(())
 }
 
-fn cleanup_transaction(
-    transaction_log: &mut HashMap<u32, TransactionState>,
-    dispute_tracker: &HashSet<u32>,
+/// Closes the open dispute on `tx`, moving the balance to the `(available, held)`
+/// that `released` computes from the amount it held. Refused while the client's
+/// `held` is below that amount, since taking it out would leave `held` negative;
+/// on any error the dispute stays open and the balance is unchanged.
+fn release_hold<A: Amount>(
+    balance: &mut ClientBalance<A>,
     tx: u32,
-) {
-    if !dispute_tracker.contains(&tx) {
-        transaction_log.remove(&tx);
+    dispute_tracker: &mut HashMap<u32, A>,
+    released: impl FnOnce(&ClientBalance<A>, A) -> Result<(A, A), SkipReason>,
+) -> Result<(), SkipReason> {
+    let &amt = dispute_tracker.get(&tx).ok_or(SkipReason::NotDisputed)?;
+    if balance.held < amt {
+        return Err(SkipReason::InsufficientHeld);
     }
+    let (available, held) = released(balance, amt)?;
+    set_balance(balance, available, held)?;
+    dispute_tracker.remove(&tx);
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::models::*;
-    use csv::ReaderBuilder;
-    use rand::Rng;
-    use rust_decimal_macros::dec;
-    use std::fs::File;
-    use std::io::{Cursor, Write};
-    use tempfile::NamedTempFile;
+/// Client id column as deserialized; see `OperationRecord`.
+trait ClientField: DeserializeOwned {
+    fn into_client_id(self, names: Option<&mut ClientInterner>) -> Result<u16>;
+}
 
-    fn create_balance() -> ClientBalance {
-        ClientBalance::new()
+impl ClientField for u16 {
+    fn into_client_id(self, _names: Option<&mut ClientInterner>) -> Result<u16> {
+        Ok(self)
     }
+}
 
-    #[test]
-    fn test_apply_deposit() {
-        let mut log = HashMap::new();
+impl ClientField for String {
+    fn into_client_id(self, names: Option<&mut ClientInterner>) -> Result<u16> {
+        names.expect("string client mode").intern(&self)
+    }
+}
+
+/// Amount column as deserialized; see `OperationRecord`.
+trait AmountField: DeserializeOwned {
+    fn into_amount(self, config: &EngineConfig) -> Result<Option<Decimal>>;
+}
+
+impl AmountField for Decimal {
+    fn into_amount(self, _config: &EngineConfig) -> Result<Option<Decimal>> {
+        Ok(Some(self))
+    }
+}
+
+impl AmountField for String {
+    fn into_amount(self, config: &EngineConfig) -> Result<Option<Decimal>> {
+        parse_amount(&self, config)
+    }
+}
+
+/// Re-attributes an open dispute from `tx` to the deposit `target`.
+///
+/// Equivalent to resolving `tx` and disputing `target` in one step: the source
+/// amount is released back to available and the target amount is held. The source
+/// stays in the log undisputed, so it can still be disputed on its own later.
+fn apply_reassign_hold<A: Amount>(
+    balance: &mut ClientBalance<A>,
+    tx: u32,
+    target: Option<u32>,
+    client: u16,
+    transaction_log: &HashMap<u32, TransactionState<A>>,
+    dispute_tracker: &mut HashMap<u32, A>,
+    config: &EngineConfig,
+) -> Result<(), SkipReason> {
+    let target = target.ok_or(SkipReason::InvalidOperation)?;
+    let source_state = logged_for(transaction_log, tx, client)?;
+    let target_state = logged_for(transaction_log, target, client)?;
+    if !source_state.is_deposit || !target_state.is_deposit {
+        return Err(SkipReason::InvalidOperation);
+    }
+    if dispute_tracker.contains_key(&target) {
+        return Err(SkipReason::AlreadyDisputed);
+    }
+    let &released = dispute_tracker.get(&tx).ok_or(SkipReason::NotDisputed)?;
+    let available = checked(balance.available.checked_add(released))?;
+    let amt =
+        hold_amount(available, target_state.amount, config).ok_or(SkipReason::InsufficientFunds)?;
+    let held = checked(balance.held.checked_sub(released))?;
+    set_balance(
+        balance,
+        checked(available.checked_sub(amt))?,
+        checked(held.checked_add(amt))?,
+    )?;
+
+    dispute_tracker.remove(&tx);
+    dispute_tracker.insert(target, amt);
+    Ok(())
+}
+
+/// Replaces an undisputed deposit with smaller deposits that sum to it.
+///
+/// Balances do not move. Each part is logged as its own deposit and can be disputed
+/// independently; the original tx is dropped from the log, so it no longer can be.
+fn apply_split<A: Amount>(
+    tx: u32,
+    client: u16,
+    parts: Option<&SplitParts>,
+    transaction_log: &mut HashMap<u32, TransactionState<A>>,
+    dispute_tracker: &HashMap<u32, A>,
+) -> Result<(), SkipReason> {
+    let SplitParts(parts) = parts.ok_or(SkipReason::InvalidOperation)?;
+    let parts = parts
+        .iter()
+        .map(|&(part_tx, amount)| Some((part_tx, A::from_decimal(amount)?)))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(SkipReason::InvalidOperation)?;
+    let state = logged_for(transaction_log, tx, client)?;
+    if dispute_tracker.contains_key(&tx) {
+        return Err(SkipReason::AlreadyDisputed);
+    }
+    let mut new_txs = HashSet::new();
+    let valid_parts = parts.iter().all(|&(part_tx, amount)| {
+        amount > A::ZERO
+            && part_tx != tx
+            && !transaction_log.contains_key(&part_tx)
+            && new_txs.insert(part_tx)
+    });
+    let total = checked(
+        parts
+            .iter()
+            .try_fold(A::ZERO, |sum, &(_, amount)| sum.checked_add(amount)),
+    )?;
+    if !state.is_deposit || parts.is_empty() || !valid_parts || total != state.amount {
+        return Err(SkipReason::InvalidOperation);
+    }
+
+    transaction_log.remove(&tx);
+    for (part_tx, amount) in parts {
+        transaction_log.insert(
+            part_tx,
+            TransactionState {
+                client,
+                amount,
+                is_deposit: true,
+            },
+        );
+    }
+    Ok(())
+}
+
+fn cleanup_transaction<A>(
+    transaction_log: &mut HashMap<u32, TransactionState<A>>,
+    dispute_tracker: &HashMap<u32, A>,
+    tx: u32,
+) {
+    if !dispute_tracker.contains_key(&tx) {
+        transaction_log.remove(&tx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::*;
+    use csv::ReaderBuilder;
+    use rand::Rng;
+    use rust_decimal::prelude::ToPrimitive;
+    use rust_decimal_macros::dec;
+    use std::fs::File;
+    use std::io::{Cursor, Write};
+    use tempfile::NamedTempFile;
+
+    fn create_balance() -> ClientBalance {
+        ClientBalance::new()
+    }
+
+    #[test]
+    fn test_deposit_overflow_rejected() {
+        let mut log = HashMap::new();
+        let mut balance = create_balance();
+        let near_max = Decimal::MAX - dec!(1);
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(near_max)).unwrap();
+        assert_eq!(
+            apply_deposit(&mut log, &mut balance, 2, 1, Some(near_max)),
+            Err(SkipReason::ArithmeticOverflow)
+        );
+        assert_eq!(balance.available, near_max);
+        assert!(!log.contains_key(&2));
+    }
+
+    #[test]
+    fn test_apply_deposit() {
+        let mut log = HashMap::new();
         let mut balance = create_balance();
-        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.1234)));
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.1234))).unwrap();
         assert_eq!(balance.available, dec!(10.1234));
         assert_eq!(balance.held, dec!(0));
         assert!(log.contains_key(&1));
@@ -205,211 +1577,1849 @@ mod tests {
         let mut log = HashMap::new();
         let mut balance = create_balance();
         balance.available = dec!(5.0);
-        apply_withdrawal(&mut balance, 1, 1, Some(dec!(3.0)), &mut log);
+        apply_withdrawal(
+            &mut balance,
+            1,
+            1,
+            Some(dec!(3.0)),
+            &mut log,
+            &EngineConfig::default(),
+        )
+        .unwrap();
         assert_eq!(balance.available, dec!(2.0));
         assert!(log.contains_key(&1));
     }
 
     #[test]
-    fn test_apply_withdrawal_fail_insufficient() {
-        let mut log = HashMap::new();
-        let mut balance = create_balance();
-        balance.available = dec!(1.0);
-        apply_withdrawal(&mut balance, 1, 1, Some(dec!(2.0)), &mut log);
-        assert_eq!(balance.available, dec!(1.0));
-        assert!(!log.contains_key(&1));
+    fn test_apply_withdrawal_fail_insufficient() {
+        let mut log = HashMap::new();
+        let mut balance = create_balance();
+        balance.available = dec!(1.0);
+        assert_eq!(
+            apply_withdrawal(
+                &mut balance,
+                1,
+                1,
+                Some(dec!(2.0)),
+                &mut log,
+                &EngineConfig::default(),
+            ),
+            Err(SkipReason::InsufficientFunds)
+        );
+        assert_eq!(balance.available, dec!(1.0));
+        assert!(!log.contains_key(&1));
+    }
+
+    #[test]
+    fn test_apply_withdrawal_fail_locked() {
+        let mut log = HashMap::new();
+        let mut balance = create_balance();
+        balance.available = dec!(5.0);
+        balance.locked = true;
+        assert_eq!(
+            apply_withdrawal(
+                &mut balance,
+                1,
+                1,
+                Some(dec!(3.0)),
+                &mut log,
+                &EngineConfig::default(),
+            ),
+            Err(SkipReason::AccountLocked)
+        );
+        assert_eq!(balance.available, dec!(5.0));
+        assert!(!log.contains_key(&1));
+    }
+
+    #[test]
+    fn test_apply_bonus() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,2.0\nbonus,1,2,5.0\n\
+                    dispute,1,2\nchargeback,1,2\npromo,2,3,1.5";
+        let mut engine = Engine::<Decimal>::new(EngineConfig::default());
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        engine.process(&mut rdr)?;
+
+        assert!(!engine.transaction_log.contains_key(&2));
+        assert_eq!(engine.client_balances[&1].available, dec!(7.0));
+        assert_eq!(engine.client_balances[&1].held, dec!(0));
+        assert!(!engine.client_balances[&1].locked);
+        assert_eq!(engine.client_balances[&2].available, dec!(1.5));
+
+        let mut locked = create_balance();
+        locked.locked = true;
+        assert!(apply_bonus(&mut locked, Some(dec!(1.0))).is_err());
+        assert!(apply_bonus(&mut locked, None).is_err());
+        assert_eq!(locked.available, dec!(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_freeze_and_unfreeze() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    freeze,1,2,\n\
+                    deposit,1,3,1.0\n\
+                    withdrawal,1,4,1.0\n\
+                    unfreeze,1,5,99.0\n\
+                    deposit,1,6,2.0\n\
+                    withdrawal,1,7,3.0\n\
+                    FREEZE,2,8,\n";
+        let mut engine = Engine::<Decimal>::new(EngineConfig::default());
+        engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+
+        let reasons: Vec<(u32, SkipReason)> =
+            engine.skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(
+            reasons,
+            [
+                (3, SkipReason::AccountLocked),
+                (4, SkipReason::AccountLocked)
+            ]
+        );
+        assert_eq!(engine.client_balances[&1].available, dec!(4.0));
+        assert!(!engine.client_balances[&1].locked);
+        assert!(engine.client_balances[&2].locked);
+        Ok(())
+    }
+
+    #[test]
+    fn test_excess_precision() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,1.12345\n\
+                    deposit,1,2,1.50000\n\
+                    withdrawal,1,3,0.00005\n";
+        let run = |excess_precision| -> Result<Engine> {
+            let config = EngineConfig {
+                excess_precision,
+                ..EngineConfig::default()
+            };
+            let mut engine = Engine::new(config);
+            engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+            Ok(engine)
+        };
+
+        let accepted = run(ExcessPrecision::Accept)?;
+        assert_eq!(accepted.client_balances[&1].available, dec!(2.62340));
+        assert!(accepted.skipped.is_empty());
+
+        let rejected = run(ExcessPrecision::Reject)?;
+        assert_eq!(rejected.client_balances[&1].available, dec!(1.5));
+        let reasons: Vec<(u32, SkipReason)> =
+            rejected.skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(
+            reasons,
+            [
+                (1, SkipReason::ExcessivePrecision),
+                (3, SkipReason::ExcessivePrecision)
+            ]
+        );
+
+        // 1.12345 rounds half-even to 1.1234; 0.00005 to 0, which is then not positive.
+        let rounded = run(ExcessPrecision::Round)?;
+        assert_eq!(rounded.client_balances[&1].available, dec!(2.6234));
+        assert_eq!(rounded.transaction_log[&1].amount, dec!(1.1234));
+        assert_eq!(rounded.skipped[0].reason, SkipReason::NonPositiveAmount);
+
+        assert_eq!(validate_amount_scale(dec!(1.1234), 4), Ok(()));
+        assert_eq!(
+            validate_amount_scale(dec!(1.12341), 4),
+            Err(SkipReason::ExcessivePrecision)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_clients_filter() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    deposit,2,2,3.0\n\
+                    deposit,3,3,1.0\n\
+                    dispute,2,2,\n\
+                    withdrawal,3,4,9.0\n\
+                    dispute,1,1,\n";
+        let config = EngineConfig {
+            clients: Some([1, 3].into()),
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::<Decimal>::new(config);
+        engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+        let mut clients: Vec<u16> = engine.client_balances.keys().copied().collect();
+        clients.sort();
+        assert_eq!(clients, [1, 3]);
+        assert_eq!(engine.client_balances[&1].held, dec!(5.0));
+        // Only the included client's own failure is reported.
+        let reasons: Vec<(u16, SkipReason)> = engine
+            .skipped
+            .iter()
+            .map(|s| (s.client, s.reason))
+            .collect();
+        assert_eq!(reasons, [(3, SkipReason::InsufficientFunds)]);
+        assert_eq!(engine.counts.records_processed, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_zero_ids() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,0,1,5.0\n\
+                    deposit,1,0,2.0\n\
+                    deposit,1,2,3.0\n";
+        let (balances, skipped) = process_transactions::<Decimal>(
+            &mut reader_builder().from_reader(data.as_bytes()),
+            &EngineConfig::default(),
+        )?;
+        assert_eq!(balances.len(), 2);
+        assert!(skipped.is_empty());
+
+        let config = EngineConfig {
+            reject_zero_ids: true,
+            ..EngineConfig::default()
+        };
+        let (balances, skipped) = process_transactions::<Decimal>(
+            &mut reader_builder().from_reader(data.as_bytes()),
+            &config,
+        )?;
+        assert_eq!(balances.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(balances[&1].available, dec!(3.0));
+        let reasons: Vec<(u16, u32, SkipReason)> =
+            skipped.iter().map(|s| (s.client, s.tx, s.reason)).collect();
+        assert_eq!(
+            reasons,
+            [
+                (0, 1, SkipReason::ReservedId),
+                (1, 0, SkipReason::ReservedId)
+            ]
+        );
+
+        // The first string client is interned as 0 but is a real client.
+        let data = "type,client,tx,amount\n\
+                    deposit,alice,1,5.0\n\
+                    deposit,bob,2,3.0\n\
+                    deposit,bob,0,1.0\n";
+        let mut engine = Engine::<Decimal>::new(EngineConfig {
+            string_clients: true,
+            ..config
+        });
+        engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+        let names = engine.client_names.as_ref().unwrap();
+        let alice = names.id("alice").unwrap();
+        assert_eq!(engine.client_balances[&alice].available, dec!(5.0));
+        let reasons: Vec<(u32, SkipReason)> =
+            engine.skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(reasons, [(0, SkipReason::ReservedId)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_withdrawals() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,4.0\nwithdrawal,1,2,10.0\n\
+                    withdrawal,1,3,1.0\ndispute,1,2,\n";
+        let run = |partial_withdrawals| -> Result<Engine> {
+            let mut engine = Engine::new(EngineConfig {
+                partial_withdrawals,
+                client_flows: true,
+                ..EngineConfig::default()
+            });
+            engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+            Ok(engine)
+        };
+
+        let whole = run(false)?;
+        assert_eq!(whole.client_balances[&1].available, dec!(3.0));
+        let reasons: Vec<(u32, SkipReason)> =
+            whole.skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(
+            reasons,
+            [
+                (2, SkipReason::InsufficientFunds),
+                (2, SkipReason::UnknownTx)
+            ]
+        );
+        assert!(whole.withdrawal_shortfalls.is_empty());
+
+        // Drained to zero; the next withdrawal finds nothing to take, and a dispute
+        // of the filled one holds what it actually withdrew.
+        let partial = run(true)?;
+        assert_eq!(partial.client_balances[&1].available, dec!(0));
+        assert_eq!(partial.client_balances[&1].held, dec!(4.0));
+        assert_eq!(
+            partial.withdrawal_shortfalls,
+            [WithdrawalShortfall {
+                client: 1,
+                tx: 2,
+                shortfall: dec!(6.0),
+            }]
+        );
+        let reasons: Vec<(u32, SkipReason)> =
+            partial.skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(reasons, [(3, SkipReason::InsufficientFunds)]);
+        assert_eq!(
+            partial.client_flows.as_ref().unwrap()[&1].withdrawn,
+            dec!(4.0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_withdrawal() {
+        let mut log = HashMap::new();
+        let config = EngineConfig {
+            max_withdrawal: Some(dec!(100)),
+            ..EngineConfig::default()
+        };
+        let mut balance = create_balance();
+        balance.available = dec!(500);
+
+        assert_eq!(
+            apply_withdrawal(&mut balance, 1, 1, Some(dec!(100.01)), &mut log, &config),
+            Err(SkipReason::ExceedsWithdrawalLimit)
+        );
+        assert_eq!(balance.available, dec!(500));
+        assert!(apply_withdrawal(&mut balance, 2, 1, Some(dec!(100)), &mut log, &config).is_ok());
+        assert_eq!(balance.available, dec!(400));
+    }
+
+    #[test]
+    fn test_withdrawal_grace() {
+        let mut log = HashMap::new();
+        let config = EngineConfig {
+            withdrawal_grace: dec!(0.0001),
+            ..EngineConfig::default()
+        };
+        let mut balance = create_balance();
+        balance.available = dec!(1.0);
+
+        assert!(apply_withdrawal(
+            &mut balance.clone(),
+            1,
+            1,
+            Some(dec!(1.00005)),
+            &mut log.clone(),
+            &EngineConfig::default()
+        )
+        .is_err());
+        assert!(
+            apply_withdrawal(&mut balance, 1, 1, Some(dec!(1.00005)), &mut log, &config).is_ok()
+        );
+        assert_eq!(balance.available, dec!(-0.00005));
+        assert!(
+            apply_withdrawal(&mut balance, 2, 1, Some(dec!(0.0001)), &mut log, &config).is_err()
+        );
+    }
+
+    #[test]
+    fn test_flat_withdrawal_fee() {
+        let mut log = HashMap::new();
+        let config = EngineConfig {
+            withdrawal_fee: WithdrawalFee::Flat(dec!(0.5)),
+            ..EngineConfig::default()
+        };
+        let mut balance = create_balance();
+        balance.available = dec!(10.0);
+
+        apply_withdrawal(&mut balance, 1, 1, Some(dec!(4.0)), &mut log, &config).unwrap();
+        assert_eq!(balance.available, dec!(5.5));
+        // 5.1 is available but 5.1 plus the fee is not.
+        assert_eq!(
+            apply_withdrawal(&mut balance, 2, 1, Some(dec!(5.1)), &mut log, &config),
+            Err(SkipReason::InsufficientFunds)
+        );
+        assert_eq!(balance.available, dec!(5.5));
+        apply_withdrawal(&mut balance, 3, 1, Some(dec!(5.0)), &mut log, &config).unwrap();
+        assert_eq!(balance.available, dec!(0));
+    }
+
+    #[test]
+    fn test_percent_withdrawal_fee() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,10\n\
+                    withdrawal,1,2,5\n\
+                    withdrawal,1,3,4.9\n\
+                    withdrawal,1,4,4.8\n";
+        let mut engine = Engine::<Decimal>::new(EngineConfig {
+            withdrawal_fee: WithdrawalFee::Percent(dec!(2)),
+            client_flows: true,
+            ..EngineConfig::default()
+        });
+        engine.process(&mut ReaderBuilder::new().from_reader(Cursor::new(data)))?;
+
+        // 5 + 0.1 fee leaves 4.9, which cannot cover 4.9 + 0.098.
+        assert_eq!(engine.skipped.len(), 1);
+        assert_eq!(engine.skipped[0].tx, 3);
+        assert_eq!(engine.skipped[0].reason, SkipReason::InsufficientFunds);
+        assert_eq!(engine.client_balances[&1].available, dec!(0.004));
+        let flows = &engine.client_flows.as_ref().unwrap()[&1];
+        assert_eq!(flows.fees, dec!(0.196));
+        assert_eq!(flows.withdrawn, dec!(9.8));
+        Ok(())
+    }
+
+    #[test]
+    fn test_withdrawal_against_held_funds() {
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let default = EngineConfig::default();
+        let include_held = EngineConfig {
+            include_held_in_withdrawable: true,
+            ..EngineConfig::default()
+        };
+        let mut balance = create_balance();
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0))).unwrap();
+        apply_deposit(&mut log, &mut balance, 2, 1, Some(dec!(4.0))).unwrap();
+        apply_dispute(&mut balance, 1, 1, None, &log, &mut tracker, &default).unwrap();
+        assert_eq!(balance.available, dec!(4.0));
+        assert_eq!(balance.held, dec!(10.0));
+
+        // With an open dispute nothing can be withdrawn unless held funds count.
+        assert_eq!(
+            apply_withdrawal(&mut balance, 4, 1, Some(dec!(4.0)), &mut log, &default),
+            Err(SkipReason::OpenDispute)
+        );
+        assert_eq!(balance.available, dec!(4.0));
+
+        assert!(apply_withdrawal(
+            &mut balance,
+            5,
+            1,
+            Some(dec!(14.0)),
+            &mut log,
+            &include_held
+        )
+        .is_ok());
+        assert_eq!(balance.available, dec!(-10.0));
+        assert_eq!(balance.held, dec!(10.0));
+        assert!(apply_withdrawal(
+            &mut balance,
+            6,
+            1,
+            Some(dec!(0.01)),
+            &mut log,
+            &include_held
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_apply_dispute() {
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let mut balance = create_balance();
+        log.insert(
+            1,
+            TransactionState {
+                client: 1,
+                amount: dec!(10.0),
+                is_deposit: true,
+            },
+        );
+        apply_dispute(
+            &mut balance,
+            1,
+            1,
+            None,
+            &log,
+            &mut tracker,
+            &EngineConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(balance.available, dec!(-10.0));
+        assert_eq!(balance.held, dec!(10.0));
+        assert!(tracker.contains_key(&1));
+    }
+
+    #[test]
+    fn test_deposit_disputes_only() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,2.0\n\
+                    dispute,1,2,\ndispute,1,1,\n";
+        let config = EngineConfig {
+            deposit_disputes_only: true,
+            ..EngineConfig::default()
+        };
+        let (balances, skipped) = process_transactions::<Decimal>(
+            &mut reader_builder().from_reader(data.as_bytes()),
+            &config,
+        )?;
+        let reasons: Vec<(u32, SkipReason)> = skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(reasons, [(2, SkipReason::DisputeOnNonDeposit)]);
+        assert_eq!(balances[&1].held, dec!(5.0));
+
+        let (balances, skipped) = process_transactions::<Decimal>(
+            &mut reader_builder().from_reader(data.as_bytes()),
+            &EngineConfig::default(),
+        )?;
+        assert!(skipped.is_empty());
+        assert_eq!(balances[&1].held, dec!(7.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_dispute() -> Result<()> {
+        let config = EngineConfig {
+            partial_disputes: true,
+            ..EngineConfig::default()
+        };
+        let run = |settle: &str| -> Result<Engine> {
+            let data =
+                format!("type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1,4.0\n{settle}");
+            let mut engine = Engine::new(config.clone());
+            engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+            Ok(engine)
+        };
+
+        let disputed = run("")?;
+        assert_eq!(disputed.client_balances[&1].available, dec!(6.0));
+        assert_eq!(disputed.client_balances[&1].held, dec!(4.0));
+        assert!(disputed.dispute_shortfalls.is_empty());
+
+        let resolved = run("resolve,1,1,\n")?;
+        assert_eq!(resolved.client_balances[&1].available, dec!(10.0));
+        assert_eq!(resolved.client_balances[&1].held, dec!(0));
+
+        let charged_back = run("chargeback,1,1,\n")?;
+        assert_eq!(charged_back.client_balances[&1].available, dec!(6.0));
+        assert_eq!(charged_back.client_balances[&1].held, dec!(0));
+        assert!(charged_back.client_balances[&1].locked);
+
+        let mut log = HashMap::new();
+        log.insert(
+            1,
+            TransactionState {
+                client: 1,
+                amount: dec!(10.0),
+                is_deposit: true,
+            },
+        );
+        let mut balance = create_balance();
+        let mut dispute = |amount| {
+            apply_dispute(
+                &mut balance,
+                1,
+                1,
+                Some(amount),
+                &log,
+                &mut HashMap::new(),
+                &config,
+            )
+        };
+        assert_eq!(dispute(dec!(10.5)), Err(SkipReason::DisputeAmountMismatch));
+        assert_eq!(dispute(dec!(0)), Err(SkipReason::ZeroDisputeAmount));
+        assert_eq!(dispute(dec!(-1)), Err(SkipReason::NonPositiveAmount));
+        assert_eq!(dispute(dec!(10.0)), Ok(()));
+
+        // A zero dispute leaves the tx open to a proper one.
+        let data = "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1,0\ndispute,1,1,2.5\n";
+        let mut engine = Engine::<Decimal>::new(config.clone());
+        engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+        let reasons: Vec<_> = engine.skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(reasons, [(1, SkipReason::ZeroDisputeAmount)]);
+        assert_eq!(engine.client_balances[&1].held, dec!(2.5));
+        Ok(())
+    }
+
+    /// Whole cents in an `i64`, standing in for a fixed-point amount type.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+    struct Cents(i64);
+
+    impl Amount for Cents {
+        const ZERO: Self = Cents(0);
+
+        fn checked_add(self, rhs: Self) -> Option<Self> {
+            self.0.checked_add(rhs.0).map(Cents)
+        }
+
+        fn checked_sub(self, rhs: Self) -> Option<Self> {
+            self.0.checked_sub(rhs.0).map(Cents)
+        }
+
+        fn from_decimal(value: Decimal) -> Option<Self> {
+            let cents = value.checked_mul(dec!(100))?;
+            if !cents.fract().is_zero() {
+                return None;
+            }
+            cents.to_i64().map(Cents)
+        }
+
+        fn to_decimal(self) -> Decimal {
+            Decimal::new(self.0, 2)
+        }
+    }
+
+    #[test]
+    fn test_balance_rules_with_integer_amounts() {
+        let config = EngineConfig {
+            withdrawal_fee: WithdrawalFee::Flat(dec!(0.10)),
+            max_disputed_amount_per_client: Some(dec!(6.00)),
+            ..EngineConfig::default()
+        };
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let mut balance = ClientBalance::<Cents>::default();
+
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(Cents(1000))).unwrap();
+        apply_deposit(&mut log, &mut balance, 2, 1, Some(Cents(500))).unwrap();
+        assert_eq!(
+            apply_deposit(&mut log, &mut balance, 2, 1, Some(Cents(1))),
+            Err(SkipReason::DuplicateTx {
+                owner: 1,
+                client: 1
+            })
+        );
+        apply_withdrawal(&mut balance, 3, 1, Some(Cents(250)), &mut log, &config).unwrap();
+        assert_eq!(balance.available, Cents(1240));
+
+        assert_eq!(
+            apply_dispute(&mut balance, 1, 1, None, &log, &mut tracker, &config),
+            Err(SkipReason::DisputeAmountCeiling)
+        );
+        apply_dispute(&mut balance, 2, 1, None, &log, &mut tracker, &config).unwrap();
+        assert_eq!((balance.available, balance.held), (Cents(740), Cents(500)));
+        assert_eq!(
+            apply_withdrawal(&mut balance, 4, 1, Some(Cents(1)), &mut log, &config),
+            Err(SkipReason::OpenDispute)
+        );
+
+        apply_chargeback(&mut balance, 2, 1, &log, &mut tracker).unwrap();
+        assert_eq!((balance.available, balance.held), (Cents(740), Cents(0)));
+        assert!(balance.locked);
+        assert_eq!(
+            apply_resolve(&mut balance, 2, 1, &log, &mut tracker),
+            Err(SkipReason::NotDisputed)
+        );
+    }
+
+    #[test]
+    fn test_engine_processes_csv_in_integer_amounts() -> Result<()> {
+        let data = "type,client,tx,amount,target,parts\n\
+                    deposit,1,1,10.00,,\n\
+                    deposit,1,2,5.00,,\n\
+                    withdrawal,1,3,2.50,,\n\
+                    deposit,2,4,1.005,,\n\
+                    deposit,2,5,3.00,,\n\
+                    split,2,5,,,51:1.00;52:2.00\n\
+                    dispute,2,52,,,\n\
+                    dispute,1,2,,,\n\
+                    reassign_hold,1,2,,1,\n\
+                    chargeback,2,52,,,\n";
+        let mut engine = Engine::<Cents>::new(EngineConfig::default());
+        engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+
+        let one = &engine.client_balances[&1];
+        assert_eq!((one.available, one.held), (Cents(250), Cents(1000)));
+        let two = &engine.client_balances[&2];
+        assert_eq!((two.available, two.held), (Cents(100), Cents(0)));
+        assert!(two.locked);
+        // Half a cent has no `Cents` value.
+        let skipped: Vec<_> = engine.skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(skipped, vec![(4, SkipReason::ExcessivePrecision)]);
+        Ok(())
+    }
+
+    fn withdrawal_log() -> HashMap<u32, TransactionState> {
+        HashMap::from([(
+            1,
+            TransactionState {
+                client: 1,
+                amount: dec!(10.0),
+                is_deposit: false,
+            },
+        )])
+    }
+
+    #[test]
+    fn test_apply_dispute_withdrawal() {
+        let log = withdrawal_log();
+        let mut tracker = HashMap::new();
+        let mut balance = create_balance();
+        apply_dispute(
+            &mut balance,
+            1,
+            1,
+            None,
+            &log,
+            &mut tracker,
+            &EngineConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(balance.available, dec!(0));
+        assert_eq!(balance.held, dec!(10.0));
+        assert!(tracker.contains_key(&1));
+    }
+
+    #[test]
+    fn test_apply_resolve_withdrawal() -> Result<()> {
+        let log = withdrawal_log();
+        let mut tracker = HashMap::from([(1, dec!(10.0))]);
+        let mut balance = create_balance();
+        balance.held = dec!(10.0);
+        apply_resolve(&mut balance, 1, 1, &log, &mut tracker).unwrap();
+        assert_eq!(balance.available, dec!(0));
+        assert_eq!(balance.held, dec!(0));
+        assert!(!balance.locked);
+        assert!(!tracker.contains_key(&1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_chargeback_withdrawal() -> Result<()> {
+        let log = withdrawal_log();
+        let mut tracker = HashMap::from([(1, dec!(10.0))]);
+        let mut balance = create_balance();
+        balance.held = dec!(10.0);
+        apply_chargeback(&mut balance, 1, 1, &log, &mut tracker).unwrap();
+        assert_eq!(balance.available, dec!(10.0));
+        assert_eq!(balance.held, dec!(0));
+        assert!(balance.locked);
+        assert!(!tracker.contains_key(&1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_withdrawal_chargeback_recredits() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,10\n\
+                    withdrawal,1,2,4\n\
+                    dispute,1,2,\n\
+                    chargeback,1,2,\n";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let (balances, skipped) =
+            process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?;
+        assert!(skipped.is_empty());
+        assert_eq!(balances[&1].available, dec!(10));
+        assert_eq!(balances[&1].held, dec!(0));
+        assert!(balances[&1].locked);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chargeback_records_locking_tx() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,10\n\
+                    deposit,1,2,5\n\
+                    dispute,1,1,\n\
+                    dispute,1,2,\n\
+                    chargeback,1,2,\n\
+                    chargeback,1,1,\n";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let (balances, _) = process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?;
+        assert!(balances[&1].locked);
+        assert_eq!(balances[&1].lock_reason, Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_dispute_ceiling() {
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let mut balance = create_balance();
+        let config = EngineConfig {
+            max_disputed_amount_per_client: Some(dec!(100)),
+            ..EngineConfig::default()
+        };
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(60))).unwrap();
+        apply_deposit(&mut log, &mut balance, 2, 1, Some(dec!(60))).unwrap();
+
+        apply_dispute(&mut balance, 1, 1, None, &log, &mut tracker, &config).unwrap();
+        assert_eq!(
+            apply_dispute(&mut balance, 2, 1, None, &log, &mut tracker, &config),
+            Err(SkipReason::DisputeAmountCeiling)
+        );
+        assert_eq!(balance.held, dec!(60));
+        assert_eq!(balance.available, dec!(60));
+        assert!(tracker.contains_key(&1));
+        assert!(!tracker.contains_key(&2));
+    }
+
+    #[test]
+    fn test_apply_resolve() -> Result<()> {
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let mut balance = create_balance();
+        log.insert(
+            1,
+            TransactionState {
+                client: 1,
+                amount: dec!(10.0),
+                is_deposit: true,
+            },
+        );
+        tracker.insert(1, dec!(10.0));
+        balance.available = dec!(-10.0);
+        balance.held = dec!(10.0);
+        apply_resolve(&mut balance, 1, 1, &log, &mut tracker).unwrap();
+        assert_eq!(balance.available, dec!(0));
+        assert_eq!(balance.held, dec!(0));
+        assert!(!tracker.contains_key(&1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_release_never_drives_held_negative() {
+        let log = HashMap::from([(
+            1,
+            TransactionState {
+                client: 1,
+                amount: dec!(10.0),
+                is_deposit: true,
+            },
+        )]);
+        let mut balance = create_balance();
+        let mut tracker = HashMap::new();
+        // A hold recorded for the full 10.0, with only 4.0 left in `held`.
+        tracker.insert(1, dec!(10.0));
+        balance.held = dec!(4.0);
+
+        assert_eq!(
+            apply_resolve(&mut balance, 1, 1, &log, &mut tracker),
+            Err(SkipReason::InsufficientHeld)
+        );
+        assert_eq!(
+            apply_chargeback(&mut balance, 1, 1, &log, &mut tracker),
+            Err(SkipReason::InsufficientHeld)
+        );
+        assert_eq!(balance.held, dec!(4.0));
+        assert_eq!(balance.available, dec!(0));
+        assert!(!balance.locked);
+        assert!(tracker.contains_key(&1));
+    }
+
+    #[test]
+    fn test_apply_chargeback() -> Result<()> {
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let mut balance = create_balance();
+        log.insert(
+            1,
+            TransactionState {
+                client: 1,
+                amount: dec!(10.0),
+                is_deposit: true,
+            },
+        );
+        tracker.insert(1, dec!(10.0));
+        balance.held = dec!(10.0);
+        apply_chargeback(&mut balance, 1, 1, &log, &mut tracker).unwrap();
+        assert_eq!(balance.available, dec!(0));
+        assert_eq!(balance.held, dec!(0));
+        assert!(balance.locked);
+        assert!(!tracker.contains_key(&1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_split() {
+        let config = EngineConfig::default();
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let mut balance = create_balance();
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0))).unwrap();
+        let parts = SplitParts(vec![(11, dec!(2.5)), (12, dec!(7.5))]);
+
+        assert!(apply_split(1, 1, Some(&parts), &mut log, &tracker).is_ok());
+        assert!(!log.contains_key(&1));
+        assert_eq!(log[&11].amount, dec!(2.5));
+        assert_eq!(log[&12].amount, dec!(7.5));
+        assert_eq!(balance.available, dec!(10.0));
+
+        assert!(apply_dispute(&mut balance, 1, 1, None, &log, &mut tracker, &config).is_err());
+        assert!(apply_dispute(&mut balance, 12, 1, None, &log, &mut tracker, &config).is_ok());
+        assert_eq!(balance.available, dec!(2.5));
+        assert_eq!(balance.held, dec!(7.5));
+    }
+
+    #[test]
+    fn test_apply_split_rejects_invalid_parts() {
+        let mut log = HashMap::new();
+        let mut balance = create_balance();
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0))).unwrap();
+        apply_deposit(&mut log, &mut balance, 2, 1, Some(dec!(1.0))).unwrap();
+        let tracker = HashMap::from([(2, dec!(1.0))]);
+        let split = |parts: Vec<(u32, Decimal)>| SplitParts(parts);
+
+        for (tx, client, parts) in [
+            (1, 1, split(vec![(11, dec!(2.5)), (12, dec!(7.0))])),
+            (1, 1, split(vec![(11, dec!(5.0)), (11, dec!(5.0))])),
+            (1, 1, split(vec![(2, dec!(5.0)), (12, dec!(5.0))])),
+            (1, 1, split(vec![(11, dec!(12.0)), (12, dec!(-2.0))])),
+            (1, 1, split(vec![])),
+            (1, 2, split(vec![(11, dec!(10.0))])),
+            (2, 1, split(vec![(11, dec!(1.0))])),
+        ] {
+            assert!(apply_split(tx, client, Some(&parts), &mut log, &tracker).is_err());
+        }
+        assert!(apply_split(1, 1, None, &mut log, &tracker).is_err());
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[&1].amount, dec!(10.0));
+    }
+
+    #[test]
+    fn test_apply_reassign_hold() {
+        let config = EngineConfig::default();
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let mut balance = create_balance();
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0))).unwrap();
+        apply_deposit(&mut log, &mut balance, 2, 1, Some(dec!(4.0))).unwrap();
+        apply_dispute(&mut balance, 1, 1, None, &log, &mut tracker, &config).unwrap();
+
+        assert!(
+            apply_reassign_hold(&mut balance, 1, Some(2), 1, &log, &mut tracker, &config).is_ok()
+        );
+        assert_eq!(balance.available, dec!(10.0));
+        assert_eq!(balance.held, dec!(4.0));
+        assert!(!tracker.contains_key(&1));
+        assert!(tracker.contains_key(&2));
+    }
+
+    #[test]
+    fn test_apply_reassign_hold_rejects_invalid_target() {
+        let config = EngineConfig::default();
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let mut balance = create_balance();
+        let mut other = create_balance();
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0))).unwrap();
+        apply_withdrawal(
+            &mut balance,
+            2,
+            1,
+            Some(dec!(1.0)),
+            &mut log,
+            &EngineConfig::default(),
+        )
+        .unwrap();
+        apply_deposit(&mut log, &mut other, 3, 2, Some(dec!(5.0))).unwrap();
+        apply_dispute(&mut balance, 1, 1, None, &log, &mut tracker, &config).unwrap();
+
+        // Withdrawal, another client's deposit, unknown tx, missing target.
+        for target in [Some(2), Some(3), Some(99), None] {
+            assert!(
+                apply_reassign_hold(&mut balance, 1, target, 1, &log, &mut tracker, &config)
+                    .is_err()
+            );
+        }
+        assert_eq!(balance.available, dec!(-1.0));
+        assert_eq!(balance.held, dec!(10.0));
+        assert!(tracker.contains_key(&1));
+    }
+
+    #[test]
+    fn test_tx_id_collision_first_occurrence_wins() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,7,5.0\n\
+                    deposit,2,7,50.0\n\
+                    dispute,1,7,\n\
+                    dispute,2,7,\n";
+        let (balances, skipped) = process_transactions::<Decimal>(
+            &mut reader_builder().from_reader(data.as_bytes()),
+            &EngineConfig::default(),
+        )?;
+        assert_eq!(balances[&1].held, dec!(5.0));
+        assert_eq!(balances[&2].total(), dec!(0));
+        let reasons: Vec<SkipReason> = skipped.iter().map(|s| s.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                SkipReason::DuplicateTx {
+                    owner: 1,
+                    client: 2
+                },
+                SkipReason::ClientMismatch {
+                    expected: 1,
+                    actual: 2
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_idempotency_duplicate_deposit() {
+        let mut log = HashMap::new();
+        let mut balance = create_balance();
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0))).unwrap();
+        assert_eq!(
+            apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0))),
+            Err(SkipReason::DuplicateTx {
+                owner: 1,
+                client: 1
+            })
+        ); // Duplicate ignored
+        assert_eq!(balance.available, dec!(10.0));
+    }
+
+    #[test]
+    fn test_negative_zero_amount_skip() {
+        let mut log = HashMap::new();
+        let mut balance = create_balance();
+        assert_eq!(
+            apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(0))),
+            Err(SkipReason::NonPositiveAmount)
+        );
+        assert_eq!(
+            apply_deposit(&mut log, &mut balance, 2, 1, Some(dec!(-1.0))),
+            Err(SkipReason::NonPositiveAmount)
+        );
+        assert_eq!(balance.available, dec!(0));
+        assert!(!log.contains_key(&1));
+        assert!(!log.contains_key(&2));
+    }
+
+    #[test]
+    fn test_post_lock_block() {
+        let mut log = HashMap::new();
+        let mut balance = create_balance();
+        balance.locked = true;
+        assert_eq!(
+            apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0))),
+            Err(SkipReason::AccountLocked)
+        );
+        assert_eq!(balance.available, dec!(0));
+    }
+
+    #[test]
+    fn test_max_tx_per_client() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,1.0\n\
+                    deposit,1,2,1.0\n\
+                    deposit,2,3,1.0\n\
+                    deposit,1,4,1.0\n\
+                    deposit,2,5,1.0\n";
+        let config = EngineConfig {
+            max_tx_per_client: Some(2),
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::<Decimal>::new(config);
+        engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+        assert_eq!(engine.client_balances[&1].available, dec!(2.0));
+        assert_eq!(engine.client_balances[&2].available, dec!(2.0));
+        assert_eq!(
+            engine.skipped,
+            [SkippedRecord {
+                tx: 4,
+                client: 1,
+                r#type: OperationType::Deposit,
+                reason: SkipReason::ClientRateLimited,
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_values() {
+        let mut log = HashMap::new();
+        let mut balance = create_balance();
+        apply_deposit(
+            &mut log,
+            &mut balance,
+            u32::MAX,
+            u16::MAX,
+            Some(dec!(10000000000.9999)),
+        )
+        .unwrap();
+        assert_eq!(balance.available, dec!(10000000000.9999));
+        assert!(log.contains_key(&u32::MAX));
+    }
+
+    #[test]
+    fn test_cleanup_after_resolve() -> Result<()> {
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let mut balance = create_balance();
+        log.insert(
+            1,
+            TransactionState {
+                client: 1,
+                amount: dec!(10.0),
+                is_deposit: true,
+            },
+        );
+        tracker.insert(1, dec!(10.0));
+        balance.held = dec!(10.0);
+        apply_resolve(&mut balance, 1, 1, &log, &mut tracker).unwrap();
+        cleanup_transaction(&mut log, &tracker, 1);
+        assert!(!log.contains_key(&1));
+        Ok(())
+    }
+
+    #[test]
+    fn integration_test_sample() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\ndeposit,1,3,2.0\nwithdrawal,1,4,1.5\nwithdrawal,2,5,3.0";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let (balances, _) = process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?;
+        assert_eq!(balances.len(), 2);
+        let b1 = balances.get(&1).unwrap();
+        assert_eq!(b1.available, dec!(1.5));
+        assert_eq!(b1.held, dec!(0.0));
+        assert!(!b1.locked);
+        let b2 = balances.get(&2).unwrap();
+        assert_eq!(b2.available, dec!(2.0)); // Withdrawal fails due to insufficient
+        assert_eq!(b2.held, dec!(0.0));
+        assert!(!b2.locked);
+        Ok(())
+    }
+
+    #[test]
+    fn integration_test_dispute_chain() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        let _ = writeln!(file, "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1\nresolve,1,1\ndeposit,1,2,5.0\ndispute,1,2\nchargeback,1,2");
+        let file_path = file.path().to_str().unwrap().to_string();
+        let file = File::open(file_path)?;
+        let mut rdr = ReaderBuilder::new().flexible(true).from_reader(file);
+        let (balances, _) = process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?;
+        let b = balances.get(&1).unwrap();
+        assert_eq!(b.available, dec!(10.0));
+        assert_eq!(b.held, dec!(0.0));
+        assert!(b.locked);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint_matches_full_run() -> Result<()> {
+        let header = "type,client,tx,amount\n";
+        let first = "deposit,1,1,10.0\ndeposit,2,2,5.5\nwithdrawal,1,3,2.25\ndispute,1,1,\n\
+                     dispute,2,2,\n";
+        let second = "resolve,1,1,\ndispute,1,1,\nchargeback,2,2,\ndeposit,2,4,1.0\n\
+                      deposit,3,5,0.0001\nwithdrawal,1,6,1.0\n";
+        let config = EngineConfig::default();
+        let process = |engine: &mut Engine, rows: &str| {
+            engine.process(&mut reader_builder().from_reader(format!("{header}{rows}").as_bytes()))
+        };
+
+        let mut full = Engine::new(config.clone());
+        process(&mut full, &format!("{first}{second}"))?;
+
+        let mut engine = Engine::new(config.clone());
+        process(&mut engine, first)?;
+        let mut saved = Vec::new();
+        engine.snapshot().write_json(&mut saved)?;
+        let state = SerializedState::read_json(saved.as_slice())?;
+        assert_eq!(state, engine.snapshot());
+        let mut resumed = Engine::restore(config, state)?;
+        process(&mut resumed, second)?;
+
+        assert_eq!(resumed.client_balances, full.client_balances);
+        assert_eq!(resumed.first_seen, full.first_seen);
+        assert_eq!(resumed.counts, full.counts);
+        assert!(resumed.client_balances[&2].locked);
+
+        let config = EngineConfig {
+            hash_chain: true,
+            ..EngineConfig::default()
+        };
+        assert!(Engine::restore(config, resumed.snapshot()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_chain_detects_changed_input() -> Result<()> {
+        let final_hash = |data: &str| -> Result<String> {
+            let config = EngineConfig {
+                hash_chain: true,
+                ..EngineConfig::default()
+            };
+            let mut engine = Engine::<Decimal>::new(config);
+            let mut rdr = ReaderBuilder::new()
+                .flexible(true)
+                .from_reader(Cursor::new(data.to_string()));
+            engine.process(&mut rdr)?;
+            Ok(engine.hash_chain.unwrap().to_hex())
+        };
+
+        let data = "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,4.0\ndispute,1,1";
+        let tampered = "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,4.5\ndispute,1,1";
+        let with_rejected = format!("{}\nwithdrawal,1,3,100.0", data);
+
+        assert_eq!(final_hash(data)?, final_hash(data)?);
+        assert_ne!(final_hash(data)?, final_hash(tampered)?);
+        assert_eq!(final_hash(data)?, final_hash(&with_rejected)?);
+        assert_ne!(final_hash(data)?, HashChain::new().to_hex());
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_hash_ignores_other_clients() -> Result<()> {
+        let run = |data: &str| -> Result<HashMap<u16, HashChain>> {
+            let mut engine = Engine::<Decimal>::new(EngineConfig {
+                client_hash: true,
+                ..EngineConfig::default()
+            });
+            let mut rdr = ReaderBuilder::new()
+                .flexible(true)
+                .from_reader(Cursor::new(data));
+            engine.process(&mut rdr)?;
+            Ok(engine.client_hashes.unwrap())
+        };
+
+        let first = run("type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,1.0\n\
+                         withdrawal,1,3,2.0\n")?;
+        let second = run("type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,3,4,7.0\n\
+                          withdrawal,1,3,2.0\nwithdrawal,1,5,99.0\n")?;
+        let changed = run("type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,3,2.5\n")?;
+
+        assert_eq!(first[&1], second[&1]);
+        assert_ne!(first[&1], changed[&1]);
+        assert_ne!(first[&1], first[&2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_client_ids() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,alice,1,10.0\ndeposit,bob-7,2,3.0\n\
+                    withdrawal,alice,3,2.5\ndispute,bob-7,2";
+        let config = EngineConfig {
+            string_clients: true,
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(config);
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        engine.process(&mut rdr)?;
+
+        let names = engine.client_names.as_ref().unwrap();
+        let by_name: HashMap<&str, &ClientBalance> = engine
+            .client_balances
+            .iter()
+            .map(|(id, balance)| (names.name(*id).unwrap(), balance))
+            .collect();
+        assert_eq!(by_name.len(), 2);
+        assert_eq!(by_name["alice"].available, dec!(7.5));
+        assert_eq!(by_name["bob-7"].available, dec!(0));
+        assert_eq!(by_name["bob-7"].held, dec!(3.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fraction_amounts_end_to_end() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,1/3\ndeposit,1,2,1/2\nwithdrawal,1,3,0.25";
+        let config = EngineConfig {
+            fraction_amounts: true,
+            ..EngineConfig::default()
+        };
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let (balances, _) = process_transactions::<Decimal>(&mut rdr, &config)?;
+        assert_eq!(balances[&1].available, dec!(0.5833));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_after_partial_withdrawal() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,100\nwithdrawal,1,2,60\ndispute,1,1";
+        let run = |dispute_hold_policy| -> Result<Engine> {
+            let mut engine = Engine::new(EngineConfig {
+                dispute_hold_policy,
+                ..EngineConfig::default()
+            });
+            let mut rdr = ReaderBuilder::new()
+                .flexible(true)
+                .from_reader(Cursor::new(data));
+            engine.process(&mut rdr)?;
+            Ok(engine)
+        };
+
+        let full = run(DisputeHoldPolicy::HoldFull)?;
+        assert_eq!(full.client_balances[&1].available, dec!(-60));
+        assert_eq!(full.client_balances[&1].held, dec!(100));
+        assert!(full.dispute_shortfalls.is_empty());
+
+        let mut partial = run(DisputeHoldPolicy::HoldAvailableOnly)?;
+        assert_eq!(partial.client_balances[&1].available, dec!(0));
+        assert_eq!(partial.client_balances[&1].held, dec!(40));
+        assert_eq!(
+            partial.dispute_shortfalls,
+            vec![DisputeShortfall {
+                client: 1,
+                tx: 1,
+                shortfall: dec!(60),
+            }]
+        );
+
+        // Resolving releases exactly what was held.
+        partial.apply(&OperationRecord {
+            r#type: OperationType::Resolve,
+            client: 1,
+            tx: 1,
+            amount: None,
+            target: None,
+            parts: None,
+        })?;
+        assert_eq!(partial.client_balances[&1].available, dec!(40));
+        assert_eq!(partial.client_balances[&1].held, dec!(0));
+
+        let rejected = run(DisputeHoldPolicy::RejectOverdispute)?;
+        assert_eq!(rejected.client_balances[&1].available, dec!(40));
+        assert_eq!(rejected.client_balances[&1].held, dec!(0));
+        assert_eq!(rejected.skipped.len(), 1);
+        assert_eq!(rejected.skipped[0].reason, SkipReason::InsufficientFunds);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_amount_must_match() {
+        let config = EngineConfig {
+            match_dispute_amount: true,
+            ..EngineConfig::default()
+        };
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let mut balance = create_balance();
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0))).unwrap();
+
+        for amount in [Some(dec!(9.0)), None] {
+            assert!(
+                apply_dispute(&mut balance, 1, 1, amount, &log, &mut tracker, &config).is_err()
+            );
+        }
+        assert_eq!(balance.held, dec!(0));
+        assert!(apply_dispute(
+            &mut balance,
+            1,
+            1,
+            Some(dec!(10.0)),
+            &log,
+            &mut tracker,
+            &config
+        )
+        .is_ok());
+        assert_eq!(balance.available, dec!(0));
+        assert_eq!(balance.held, dec!(10.0));
+    }
+
+    #[test]
+    fn test_reject_overdispute() {
+        let config = EngineConfig {
+            dispute_hold_policy: DisputeHoldPolicy::RejectOverdispute,
+            ..EngineConfig::default()
+        };
+        let mut log = HashMap::new();
+        let mut tracker = HashMap::new();
+        let mut balance = create_balance();
+        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(50))).unwrap();
+        apply_withdrawal(&mut balance, 2, 1, Some(dec!(50)), &mut log, &config).unwrap();
+
+        assert!(apply_dispute(&mut balance, 1, 1, None, &log, &mut tracker, &config).is_err());
+        assert_eq!(balance.available, dec!(0));
+        assert_eq!(balance.held, dec!(0));
+        assert!(tracker.is_empty());
+
+        // Still disputable once the funds are back.
+        apply_deposit(&mut log, &mut balance, 3, 1, Some(dec!(50))).unwrap();
+        assert!(apply_dispute(&mut balance, 1, 1, None, &log, &mut tracker, &config).is_ok());
+        assert_eq!(balance.available, dec!(0));
+        assert_eq!(balance.held, dec!(50));
+    }
+
+    #[test]
+    fn test_resolve_open_disputes() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,4.0\n\
+                    deposit,1,3,1.0\ndispute,1,1\ndispute,2,2\ndispute,1,3\nresolve,1,3";
+        let mut engine = Engine::<Decimal>::new(EngineConfig::default());
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        engine.process(&mut rdr)?;
+        assert_eq!(engine.client_balances[&1].held, dec!(10.0));
+
+        assert_eq!(engine.resolve_open_disputes()?, 2);
+        assert!(engine.dispute_tracker.is_empty());
+        assert_eq!(engine.client_balances[&1].available, dec!(11.0));
+        assert_eq!(engine.client_balances[&1].held, dec!(0));
+        assert_eq!(engine.client_balances[&2].available, dec!(4.0));
+        assert_eq!(engine.client_balances[&2].held, dec!(0));
+        assert_eq!(engine.resolve_open_disputes()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_open_disputes_counts_only_applied() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1\n\
+                    deposit,2,2,4.0\ndeposit,2,3,1.0\ndispute,2,2\n";
+        let config = EngineConfig::builder().max_tx_per_client(3).build();
+        let mut engine = Engine::<Decimal>::new(config);
+        engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+
+        // Client 2 has used its three operations, so its resolve is skipped.
+        assert_eq!(engine.resolve_open_disputes()?, 1);
+        assert_eq!(engine.client_balances[&1].held, dec!(0));
+        assert_eq!(engine.client_balances[&2].held, dec!(4.0));
+        let reasons: Vec<_> = engine.skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(reasons, [(2, SkipReason::ClientRateLimited)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_net_flow_ignores_chargeback() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,3.0\n\
+                    deposit,1,3,5.0\ndispute,1,3\nchargeback,1,3\nwithdrawal,1,4,100.0";
+        let config = EngineConfig {
+            client_flows: true,
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::<Decimal>::new(config);
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        engine.process(&mut rdr)?;
+
+        let flows = &engine.client_flows.as_ref().unwrap()[&1];
+        assert_eq!((flows.deposit_count, flows.withdrawal_count), (2, 1));
+        assert_eq!(flows.deposited, dec!(15.0));
+        assert_eq!(flows.withdrawn, dec!(3.0));
+        assert_eq!(flows.net_flow(), dec!(12.0));
+        assert_eq!(engine.client_balances[&1].available, dec!(7.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_null_token_deposit_is_missing_amount() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,null\ndeposit,1,2,2.5\ndispute,1,1";
+        let config = EngineConfig {
+            null_tokens: true,
+            ..EngineConfig::default()
+        };
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let (balances, _) = process_transactions::<Decimal>(&mut rdr, &config)?;
+        assert_eq!(balances[&1].available, dec!(2.5));
+        assert_eq!(balances[&1].held, dec!(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_skipped_records_carry_reasons() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    deposit,1,1,5.0\n\
+                    withdrawal,1,2,9.0\n\
+                    deposit,1,3,0\n\
+                    withdrawal,1,5,1.0\n\
+                    resolve,1,5,\n\
+                    dispute,1,7,\n\
+                    dispute,1,1,\n\
+                    dispute,1,1,\n\
+                    chargeback,1,1,\n\
+                    deposit,1,4,1.0";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let (balances, skipped) =
+            process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?;
+        assert!(balances[&1].locked);
+
+        let reasons: Vec<(u32, SkipReason)> = skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(
+            reasons,
+            [
+                (
+                    1,
+                    SkipReason::DuplicateTx {
+                        owner: 1,
+                        client: 1
+                    }
+                ),
+                (2, SkipReason::InsufficientFunds),
+                (3, SkipReason::NonPositiveAmount),
+                (5, SkipReason::NotDisputed),
+                (7, SkipReason::UnknownTx),
+                (1, SkipReason::AlreadyDisputed),
+                (4, SkipReason::AccountLocked),
+            ]
+        );
+        assert_eq!(skipped[0].r#type, OperationType::Deposit);
+        assert_eq!(skipped[0].client, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_redispute_after_settling() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    dispute,1,1,\n\
+                    resolve,1,1,\n\
+                    dispute,1,1,\n\
+                    dispute,1,2,\n";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let (balances, skipped) =
+            process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?;
+        assert_eq!(balances[&1].available, dec!(5.0));
+        assert_eq!(balances[&1].held, dec!(0));
+
+        let reasons: Vec<(u32, SkipReason)> = skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(
+            reasons,
+            [
+                (1, SkipReason::DisputeAlreadyUsed),
+                (2, SkipReason::UnknownTx)
+            ]
+        );
+
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    dispute,1,1,\n\
+                    chargeback,1,1,\n\
+                    dispute,1,1,\n";
+        let (_, skipped) = process_transactions::<Decimal>(
+            &mut reader_builder().from_reader(data.as_bytes()),
+            &EngineConfig::default(),
+        )?;
+        let reasons: Vec<(u32, SkipReason)> = skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(reasons, [(1, SkipReason::AlreadySettled)]);
+        Ok(())
     }
 
     #[test]
-    fn test_apply_withdrawal_fail_locked() {
-        let mut log = HashMap::new();
-        let mut balance = create_balance();
-        balance.available = dec!(5.0);
-        balance.locked = true;
-        apply_withdrawal(&mut balance, 1, 1, Some(dec!(3.0)), &mut log);
-        assert_eq!(balance.available, dec!(5.0));
-        assert!(!log.contains_key(&1));
+    fn test_dispute_policy() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    dispute,1,1,\n\
+                    resolve,1,1,\n\
+                    dispute,1,1,\n";
+        let run = |dispute_policy| {
+            let config = EngineConfig {
+                dispute_policy,
+                ..EngineConfig::default()
+            };
+            process_transactions::<Decimal>(
+                &mut reader_builder().from_reader(data.as_bytes()),
+                &config,
+            )
+        };
+
+        let (balances, skipped) = run(DisputePolicy::Once)?;
+        assert_eq!(balances[&1].held, dec!(0));
+        assert_eq!(skipped[0].reason, SkipReason::DisputeAlreadyUsed);
+
+        let (balances, skipped) = run(DisputePolicy::Multiple)?;
+        assert_eq!(balances[&1].available, dec!(0));
+        assert_eq!(balances[&1].held, dec!(5.0));
+        assert!(skipped.is_empty());
+        Ok(())
     }
 
     #[test]
-    fn test_apply_dispute() {
-        let mut log = HashMap::new();
-        let mut tracker = HashSet::new();
-        let mut balance = create_balance();
-        log.insert(
-            1,
-            TransactionState {
-                client: 1,
-                amount: dec!(10.0),
-                is_deposit: true,
-            },
+    fn test_amount_presence_is_validated() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    deposit,1,2,\n\
+                    dispute,1,1,5.0\n\
+                    dispute,1,1,\n";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let (balances, skipped) =
+            process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?;
+        assert_eq!(balances[&1].held, dec!(5.0));
+
+        let reasons: Vec<(u32, SkipReason)> = skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(
+            reasons,
+            [
+                (2, SkipReason::MalformedRecord("missing amount")),
+                (
+                    1,
+                    SkipReason::MalformedRecord("unexpected amount on dispute")
+                ),
+            ]
         );
-        apply_dispute(&mut balance, 1, 1, &log, &mut tracker);
-        assert_eq!(balance.available, dec!(-10.0));
-        assert_eq!(balance.held, dec!(10.0));
-        assert!(tracker.contains(&1));
+
+        // Under `match_dispute_amount` the dispute is expected to carry its amount.
+        let config = EngineConfig {
+            match_dispute_amount: true,
+            ..EngineConfig::default()
+        };
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let (balances, skipped) = process_transactions::<Decimal>(&mut rdr, &config)?;
+        assert_eq!(balances[&1].held, dec!(5.0));
+        assert_eq!(skipped[1].reason, SkipReason::AlreadyDisputed);
+        Ok(())
     }
 
     #[test]
-    fn test_apply_dispute_ignore_non_deposit() {
-        let mut log = HashMap::new();
-        let mut tracker = HashSet::new();
-        let mut balance = create_balance();
-        log.insert(
-            1,
-            TransactionState {
-                client: 1,
-                amount: dec!(10.0),
-                is_deposit: false,
-            },
+    fn test_check_header() {
+        let check = |data: &str| check_header(&mut reader_builder().from_reader(Cursor::new(data)));
+
+        assert!(check("type,client,tx,amount\ndeposit,1,1,1.0\n").is_ok());
+        assert!(check("client, amount, tx, type\n1,1.0,1,deposit\n").is_ok());
+        assert!(check("type,client,tx,amount,target,parts\n").is_ok());
+        assert!(check("").is_ok());
+
+        let err = check("type,client,transaction,amount\ndeposit,1,1,1.0\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid input header 'type,client,transaction,amount': missing tx; \
+             unexpected transaction (expected type,client,tx,amount)"
         );
-        apply_dispute(&mut balance, 1, 1, &log, &mut tracker);
-        assert_eq!(balance.available, dec!(0));
-        assert_eq!(balance.held, dec!(0));
-        assert!(!tracker.contains(&1));
+        let err = check("type,client,tx,amount,amount\n").unwrap_err();
+        assert!(err.to_string().contains(": duplicate amount ("));
+        let err = check("deposit,1,1,1.0\nwithdrawal,1,2,0.5\n").unwrap_err();
+        assert!(err.to_string().contains("'deposit,1,1,1.0'"));
+
+        // Rejected before any row is applied.
+        let mut engine = Engine::<Decimal>::new(EngineConfig::default());
+        let data = "type,client,transaction,amount\ndeposit,1,1,1.0\n";
+        assert!(engine
+            .process(&mut reader_builder().from_reader(Cursor::new(data)))
+            .is_err());
+        assert!(engine.client_balances.is_empty());
+    }
+
+    /// Records log messages emitted on the current thread, so parallel tests do not
+    /// see each other's.
+    struct CapturingLogger;
+
+    thread_local! {
+        static CAPTURED: std::cell::RefCell<Vec<(log::Level, String)>> =
+            const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED.with(|captured| {
+                captured
+                    .borrow_mut()
+                    .push((record.level(), record.args().to_string()))
+            });
+        }
+
+        fn flush(&self) {}
     }
 
     #[test]
-    fn test_apply_resolve() -> Result<()> {
-        let mut log = HashMap::new();
-        let mut tracker = HashSet::new();
-        let mut balance = create_balance();
-        log.insert(
-            1,
-            TransactionState {
-                client: 1,
-                amount: dec!(10.0),
-                is_deposit: true,
-            },
-        );
-        tracker.insert(1);
-        balance.available = dec!(-10.0);
-        balance.held = dec!(10.0);
-        apply_resolve(&mut balance, 1, 1, &log, &mut tracker)?;
-        assert_eq!(balance.available, dec!(0));
-        assert_eq!(balance.held, dec!(0));
-        assert!(!tracker.contains(&1));
+    fn test_skips_are_logged() -> Result<()> {
+        static LOGGER: std::sync::Once = std::sync::Once::new();
+        LOGGER.call_once(|| {
+            log::set_logger(&CapturingLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+        });
+
+        let data = "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,9.0\n";
+        let mut rdr = ReaderBuilder::new().from_reader(Cursor::new(data));
+        process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?;
+
+        let captured = CAPTURED.with(|captured| captured.take());
+        assert!(captured.contains(&(
+            log::Level::Warn,
+            "skipped Withdrawal tx 2 for client 1: InsufficientFunds".to_string()
+        )));
+        assert!(captured.contains(&(
+            log::Level::Info,
+            "processed 2 records: 1 skipped, 0 disputes opened".to_string()
+        )));
         Ok(())
     }
 
     #[test]
-    fn test_apply_chargeback() -> Result<()> {
-        let mut log = HashMap::new();
-        let mut tracker = HashSet::new();
-        let mut balance = create_balance();
-        log.insert(
-            1,
-            TransactionState {
-                client: 1,
-                amount: dec!(10.0),
-                is_deposit: true,
-            },
+    fn test_strict_mode_rejects_bad_withdrawal() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    withdrawal,1,2,9.0\n\
+                    deposit,1,3,1.0";
+        let reader = || {
+            ReaderBuilder::new()
+                .flexible(true)
+                .from_reader(Cursor::new(data))
+        };
+
+        let (balances, skipped) =
+            process_transactions::<Decimal>(&mut reader(), &EngineConfig::default())?;
+        assert_eq!(balances[&1].available, dec!(6.0));
+        assert_eq!(skipped.len(), 1);
+
+        let strict = EngineConfig {
+            processing_mode: ProcessingMode::Strict,
+            ..EngineConfig::default()
+        };
+        let err = process_transactions::<Decimal>(&mut reader(), &strict).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Withdrawal tx 2 for client 1 rejected: InsufficientFunds"
         );
-        tracker.insert(1);
-        balance.held = dec!(10.0);
-        apply_chargeback(&mut balance, 1, 1, &log, &mut tracker)?;
-        assert_eq!(balance.available, dec!(0));
-        assert_eq!(balance.held, dec!(0));
-        assert!(balance.locked);
-        assert!(!tracker.contains(&1));
         Ok(())
     }
 
     #[test]
-    fn test_idempotency_duplicate_deposit() {
-        let mut log = HashMap::new();
-        let mut balance = create_balance();
-        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0)));
-        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0))); // Duplicate ignored
-        assert_eq!(balance.available, dec!(10.0));
-    }
+    fn test_ragged_rows_under_strict() -> Result<()> {
+        let strict = EngineConfig {
+            processing_mode: ProcessingMode::Strict,
+            ..EngineConfig::default()
+        };
+        let run = |rows: &str, config: &EngineConfig| {
+            let data = format!("type,client,tx,amount\ndeposit,1,1,5.0\n{rows}");
+            process_transactions::<Decimal>(
+                &mut reader_builder().from_reader(data.as_bytes()),
+                config,
+            )
+        };
 
-    #[test]
-    fn test_negative_zero_amount_skip() {
-        let mut log = HashMap::new();
-        let mut balance = create_balance();
-        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(0)));
-        apply_deposit(&mut log, &mut balance, 2, 1, Some(dec!(-1.0)));
-        assert_eq!(balance.available, dec!(0));
-        assert!(!log.contains_key(&1));
-        assert!(!log.contains_key(&2));
+        // Rows may end before columns their type does not use.
+        let (balances, _) = run("dispute,1,1\nresolve,1,1\n", &strict)?;
+        assert_eq!(balances[&1].available, dec!(5.0));
+
+        for (rows, actual) in [("deposit,1,2\n", 3), ("deposit,1,2,1.0,extra\n", 5)] {
+            let err = run(rows, &strict).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "Deposit tx 2 for client 1 rejected: MalformedRow {{ expected: 4, actual: {actual} }}"
+                )
+            );
+        }
+
+        // Without strict, a missing amount reads as none and an extra field is ignored.
+        let (balances, skipped) = run(
+            "deposit,1,2\ndeposit,1,3,1.0,extra\n",
+            &EngineConfig::default(),
+        )?;
+        assert_eq!(balances[&1].available, dec!(6.0));
+        let reasons: Vec<_> = skipped.iter().map(|s| (s.tx, s.reason)).collect();
+        assert_eq!(
+            reasons,
+            [(2, SkipReason::MalformedRecord("missing amount"))]
+        );
+        Ok(())
     }
 
     #[test]
-    fn test_post_lock_block() {
-        let mut log = HashMap::new();
-        let mut balance = create_balance();
-        balance.locked = true;
-        apply_deposit(&mut log, &mut balance, 1, 1, Some(dec!(10.0)));
-        assert_eq!(balance.available, dec!(0));
+    fn test_progress_counts_every_row() -> Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&reports);
+        let mut engine = Engine::<Decimal>::new(EngineConfig::default());
+        engine.progress = Some(Progress::new(2, move |rows, _| {
+            sink.lock().unwrap().push(rows)
+        }));
+        // Skipped rows count too, and the count carries over into the next input.
+        let day1 = "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,9.0\ndeposit,1,3,1.0\n";
+        let day2 = "type,client,tx,amount\ndispute,1,1,\nresolve,1,1,\n";
+        for data in [day1, day2] {
+            engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+        }
+        assert_eq!(engine.progress.as_ref().map(Progress::rows), Some(5));
+        assert_eq!(*reports.lock().unwrap(), [2, 4]);
+        Ok(())
     }
 
     #[test]
-    fn test_max_values() {
-        let mut log = HashMap::new();
-        let mut balance = create_balance();
-        apply_deposit(
-            &mut log,
-            &mut balance,
-            u32::MAX,
-            u16::MAX,
-            Some(dec!(10000000000.9999)),
-        );
-        assert_eq!(balance.available, dec!(10000000000.9999));
-        assert!(log.contains_key(&u32::MAX));
+    fn test_observer_sees_applied_operations_only() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    withdrawal,1,2,9.0\n\
+                    deposit,2,3,1.0\n\
+                    dispute,1,1,";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let mut seen = Vec::new();
+        process_transactions_with_observer::<Decimal>(
+            &mut rdr,
+            &EngineConfig::default(),
+            &mut |record, balance, _| seen.push((record.tx, balance.clone())),
+        )?;
+
+        let txs: Vec<u32> = seen.iter().map(|(tx, _)| *tx).collect();
+        assert_eq!(txs, [1, 3, 1]);
+        assert_eq!(seen[0].1.available, dec!(5.0));
+        assert_eq!(seen[2].1.available, dec!(0));
+        assert_eq!(seen[2].1.held, dec!(5.0));
+        Ok(())
     }
 
     #[test]
-    fn test_cleanup_after_resolve() -> Result<()> {
-        let mut log = HashMap::new();
-        let mut tracker = HashSet::new();
-        let mut balance = create_balance();
-        log.insert(
-            1,
-            TransactionState {
-                client: 1,
-                amount: dec!(10.0),
-                is_deposit: true,
-            },
+    fn test_unknown_client_gets_no_balance() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    dispute,99,1,\n\
+                    withdrawal,98,2,1.0\n\
+                    resolve,97,1,\n";
+        let (balances, skipped) = process_transactions::<Decimal>(
+            &mut reader_builder().from_reader(data.as_bytes()),
+            &EngineConfig::default(),
+        )?;
+        assert_eq!(balances.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(balances[&1].held, dec!(0));
+        let reasons: Vec<(u16, SkipReason)> =
+            skipped.iter().map(|s| (s.client, s.reason)).collect();
+        assert_eq!(
+            reasons,
+            [
+                (99, SkipReason::UnknownClient),
+                (98, SkipReason::UnknownClient),
+                (97, SkipReason::UnknownClient)
+            ]
         );
-        tracker.insert(1);
-        apply_resolve(&mut balance, 1, 1, &log, &mut tracker)?;
-        cleanup_transaction(&mut log, &tracker, 1);
-        assert!(!log.contains_key(&1));
         Ok(())
     }
 
     #[test]
-    fn integration_test_sample() -> Result<()> {
-        let data = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\ndeposit,1,3,2.0\nwithdrawal,1,4,1.5\nwithdrawal,2,5,3.0";
+    fn test_dispute_client_mismatch() -> Result<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,1.0\n\
+                    dispute,2,1,\nchargeback,2,1,";
         let mut rdr = ReaderBuilder::new()
             .flexible(true)
             .from_reader(Cursor::new(data));
-        let balances = process_transactions(&mut rdr)?;
-        assert_eq!(balances.len(), 2);
-        let b1 = balances.get(&1).unwrap();
-        assert_eq!(b1.available, dec!(1.5));
-        assert_eq!(b1.held, dec!(0.0));
-        assert!(!b1.locked);
-        let b2 = balances.get(&2).unwrap();
-        assert_eq!(b2.available, dec!(2.0)); // Withdrawal fails due to insufficient
-        assert_eq!(b2.held, dec!(0.0));
-        assert!(!b2.locked);
+        let (balances, skipped) =
+            process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?;
+
+        let mismatch = SkipReason::ClientMismatch {
+            expected: 1,
+            actual: 2,
+        };
+        let reasons: Vec<SkipReason> = skipped.iter().map(|s| s.reason).collect();
+        assert_eq!(reasons, [mismatch, mismatch]);
+        assert_eq!(balances[&1].available, dec!(5.0));
+        assert_eq!(balances[&1].held, dec!(0));
+        assert!(!balances[&1].locked);
         Ok(())
     }
 
     #[test]
-    fn integration_test_dispute_chain() -> Result<()> {
-        let mut file = NamedTempFile::new()?;
-        let _ = writeln!(file, "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1\nresolve,1,1\ndeposit,1,2,5.0\ndispute,1,2\nchargeback,1,2");
-        let file_path = file.path().to_str().unwrap().to_string();
-        let file = File::open(file_path)?;
-        let mut rdr = ReaderBuilder::new().flexible(true).from_reader(file);
-        let balances = process_transactions(&mut rdr)?;
-        let b = balances.get(&1).unwrap();
-        assert_eq!(b.available, dec!(10.0));
-        assert_eq!(b.held, dec!(0.0));
-        assert!(b.locked);
+    fn test_no_withdrawal_while_dispute_open() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,10.0\n\
+                    withdrawal,1,2,10.0\n\
+                    dispute,1,1,\n\
+                    deposit,1,3,15.0\n\
+                    withdrawal,1,4,5.0\n\
+                    chargeback,1,1,\n\
+                    withdrawal,1,5,5.0";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let (balances, skipped) =
+            process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?;
+
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(
+            (skipped[0].tx, skipped[0].reason),
+            (4, SkipReason::OpenDispute)
+        );
+        // Locked by the chargeback, which the deposit made after the dispute covered.
+        assert_eq!(
+            (skipped[1].tx, skipped[1].reason),
+            (5, SkipReason::AccountLocked)
+        );
+        assert_eq!(balances[&1].available, dec!(5.0));
+        assert_eq!(balances[&1].held, dec!(0));
         Ok(())
     }
 
@@ -452,7 +3462,7 @@ mod tests {
             // If buffer is exhausted, generate more lines
             if self.buffer_pos >= self.buffer.len() {
                 if self.current_tx >= self.total_txs {
-                    return Ok(0); 
+                    return Ok(0);
                 }
                 self.buffer.clear();
                 self.buffer_pos = 0;
@@ -474,6 +3484,251 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parallel_matches_serial() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,10.0\n\
+                    deposit,2,2,5.0\n\
+                    deposit,3,3,7.5\n\
+                    withdrawal,1,4,3.0\n\
+                    dispute,2,2,\n\
+                    deposit,4,5,1.0\n\
+                    chargeback,2,2,\n\
+                    withdrawal,3,6,100.0\n\
+                    dispute,1,1,\n\
+                    resolve,1,1,\n\
+                    dispute,4,2,";
+        let reader = || {
+            ReaderBuilder::new()
+                .flexible(true)
+                .from_reader(Cursor::new(data))
+        };
+        let (serial, serial_skipped) =
+            process_transactions::<Decimal>(&mut reader(), &EngineConfig::default())?;
+        for num_threads in [1, 2, 3, 8] {
+            let (parallel, mut skipped) = process_transactions_parallel(
+                &mut reader(),
+                &EngineConfig::default(),
+                num_threads,
+            )?;
+            assert_eq!(parallel, serial);
+            skipped.sort_by_key(|s| s.tx);
+            let mut expected = serial_skipped.clone();
+            expected.sort_by_key(|s| s.tx);
+            assert_eq!(skipped, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_matches_serial_with_reused_tx_ids() -> Result<()> {
+        // Client 2 reuses client 1's deposit id; the rows after it must still be
+        // judged against the whole ledger.
+        let data = "type,client,tx,amount\n\
+                    deposit,1,7,5.0\n\
+                    deposit,3,8,2.0\n\
+                    deposit,2,7,3.0\n\
+                    dispute,2,7,\n\
+                    dispute,1,7,\n\
+                    deposit,2,9,1.0\n\
+                    withdrawal,3,10,1.5\n\
+                    chargeback,1,7,";
+        let reader = || {
+            ReaderBuilder::new()
+                .flexible(true)
+                .from_reader(Cursor::new(data))
+        };
+        let (serial, mut serial_skipped) =
+            process_transactions::<Decimal>(&mut reader(), &EngineConfig::default())?;
+        serial_skipped.sort_by_key(|s| (s.tx, s.client));
+        assert!(serial_skipped.iter().any(|s| s.tx == 7
+            && s.client == 2
+            && matches!(s.reason, SkipReason::DuplicateTx { .. })));
+        for num_threads in [1, 2, 3, 8] {
+            let (parallel, mut skipped) = process_transactions_parallel(
+                &mut reader(),
+                &EngineConfig::default(),
+                num_threads,
+            )?;
+            assert_eq!(parallel, serial);
+            skipped.sort_by_key(|s| (s.tx, s.client));
+            assert_eq!(skipped, serial_skipped);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_rejects_run_wide_options() {
+        let data = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+        for config in [
+            EngineConfig::builder().dispute_window(10).build(),
+            EngineConfig::builder().string_clients(true).build(),
+        ] {
+            let mut rdr = ReaderBuilder::new().from_reader(Cursor::new(data));
+            assert!(process_transactions_parallel(&mut rdr, &config, 2).is_err());
+        }
+    }
+
+    #[test]
+    fn test_client_interleaving_does_not_change_results() -> Result<()> {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        // Each client's rows in the order they must be applied in.
+        let clients: Vec<Vec<String>> = (1..=6u32)
+            .map(|c| {
+                let tx = |n: u32| c * 100 + n;
+                vec![
+                    format!("deposit,{c},{},{c}.5", tx(1)),
+                    format!("withdrawal,{c},{},1.25", tx(2)),
+                    format!("deposit,{c},{},0.0{c}", tx(3)),
+                    format!("dispute,{c},{},", tx(1)),
+                    if c % 2 == 0 {
+                        format!("chargeback,{c},{},", tx(1))
+                    } else {
+                        format!("resolve,{c},{},", tx(1))
+                    },
+                    format!("withdrawal,{c},{},0.5", tx(4)),
+                ]
+            })
+            .collect();
+        let run = |rows: &[&String]| -> Result<Vec<u8>> {
+            let data = format!(
+                "type,client,tx,amount\n{}\n",
+                rows.iter()
+                    .map(|row| row.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+            let mut engine = Engine::new(EngineConfig::default());
+            engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+            let mut out = Vec::new();
+            crate::output::write_csv(
+                &engine.client_balances,
+                &crate::output::OutputOptions::default(),
+                &mut out,
+            )?;
+            Ok(out)
+        };
+
+        let in_client_order: Vec<&String> = clients.iter().flatten().collect();
+        let expected = run(&in_client_order)?;
+        let mut rng = StdRng::seed_from_u64(286);
+        for _ in 0..50 {
+            // Interleave the clients at random, keeping each client's own order.
+            let mut cursors = vec![0; clients.len()];
+            let mut rows = Vec::new();
+            while rows.len() < in_client_order.len() {
+                let c = rng.gen_range(0..clients.len());
+                if let Some(row) = clients[c].get(cursors[c]) {
+                    rows.push(row);
+                    cursors[c] += 1;
+                }
+            }
+            assert_eq!(run(&rows)?, expected);
+        }
+
+        // Within a client, order is significant: a withdrawal ahead of the deposit
+        // funding it fails.
+        let (funded, _) = process_transactions::<Decimal>(
+            &mut reader_builder().from_reader(
+                "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,5.0\n".as_bytes(),
+            ),
+            &EngineConfig::default(),
+        )?;
+        let (unfunded, skipped) = process_transactions::<Decimal>(
+            &mut reader_builder().from_reader(
+                "type,client,tx,amount\nwithdrawal,1,2,5.0\ndeposit,1,1,5.0\n".as_bytes(),
+            ),
+            &EngineConfig::default(),
+        )?;
+        assert_eq!(funded[&1].available, dec!(0));
+        assert_eq!(unfunded[&1].available, dec!(5.0));
+        assert_eq!(skipped[0].reason, SkipReason::UnknownClient);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_strict_error() {
+        let data = "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,2,2,5.0";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let strict = EngineConfig {
+            processing_mode: ProcessingMode::Strict,
+            ..EngineConfig::default()
+        };
+        let err = process_transactions_parallel(&mut rdr, &strict, 4).unwrap_err();
+        assert!(err.to_string().contains("tx 2"));
+    }
+
+    #[test]
+    fn test_dispute_window_evicts_oldest() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,1.0\n\
+                    deposit,1,2,2.0\n\
+                    dispute,1,2,\n\
+                    deposit,1,3,3.0\n\
+                    deposit,1,4,4.0\n\
+                    dispute,1,1,\n\
+                    resolve,1,2,\n\
+                    dispute,1,3,";
+        let config = EngineConfig {
+            dispute_window: Some(2),
+            ..EngineConfig::default()
+        };
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let (balances, skipped) = process_transactions::<Decimal>(&mut rdr, &config)?;
+
+        // tx 1 fell out of the window; tx 2 did too, but was kept while disputed.
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(
+            (skipped[0].tx, skipped[0].reason),
+            (1, SkipReason::UnknownTx)
+        );
+        assert_eq!(balances[&1].held, dec!(3.0));
+        assert_eq!(balances[&1].available, dec!(7.0));
+        Ok(())
+    }
+
+    /// Serial vs sharded throughput on the same generated workload.
+    #[test]
+    #[ignore]
+    fn perf_test_parallel() -> Result<()> {
+        let num_txs = 2_000_000;
+        let num_clients = 1000u16;
+        let threads = thread::available_parallelism().map_or(4, |n| n.get());
+
+        let time = |num_threads: Option<usize>| -> Result<f64> {
+            let generator = StreamingCsvGenerator::new(num_txs, num_clients);
+            let mut rdr = ReaderBuilder::new().flexible(true).from_reader(generator);
+            let start = std::time::Instant::now();
+            let (balances, _) = match num_threads {
+                None => process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?,
+                Some(n) => process_transactions_parallel(&mut rdr, &EngineConfig::default(), n)?,
+            };
+            let duration = start.elapsed().as_secs_f64();
+            let total: Decimal = balances.values().map(|b| b.available).sum();
+            assert_eq!(total, Decimal::from(num_txs));
+            Ok(duration)
+        };
+
+        let serial = time(None)?;
+        let parallel = time(Some(threads))?;
+        println!(
+            "{} txs: serial {:.2}s ({:.0} tx/sec), {} threads {:.2}s ({:.0} tx/sec)",
+            num_txs,
+            serial,
+            num_txs as f64 / serial,
+            threads,
+            parallel,
+            num_txs as f64 / parallel
+        );
+        Ok(())
+    }
+
     #[test]
     #[ignore]
     fn perf_test_large_dataset() -> Result<()> {
@@ -482,13 +3737,12 @@ mod tests {
 
         // Create streaming generator (no pre-allocation of transactions)
         let generator = StreamingCsvGenerator::new(num_txs, num_clients);
-        let mut rdr = ReaderBuilder::new()
-            .flexible(true)
-            .from_reader(generator);
+        let mut rdr = ReaderBuilder::new().flexible(true).from_reader(generator);
 
         // Measure processing time
         let start = std::time::Instant::now();
-        let client_balances = process_transactions(&mut rdr)?;
+        let (client_balances, _) =
+            process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())?;
         let duration = start.elapsed().as_secs_f64();
 
         // Estimate memory (only stores client balances + transaction log for disputes)
@@ -509,11 +3763,7 @@ mod tests {
         if cfg!(debug_assertions) {
             assert!(duration < 60.0, "Debug mode too slow: {:.2}s", duration);
         } else {
-            assert!(
-                duration < 20.0,
-                "Release mode too slow: {:.2}s",
-                duration
-            );
+            assert!(duration < 20.0, "Release mode too slow: {:.2}s", duration);
         }
         assert!(mem_mb < 500.0, "Memory usage too high: {:.2}MB", mem_mb);
 
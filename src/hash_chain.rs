@@ -0,0 +1,59 @@
+use sha2::{Digest, Sha256};
+
+use crate::models::OperationRecord;
+
+/// Rolling SHA-256 over every applied operation: `next = sha256(prev || event)`.
+///
+/// The final value changes if any applied operation (or the order they were applied
+/// in) changes, so comparing it between runs detects altered input or processing.
+/// Rejected operations do not contribute.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashChain {
+    current: [u8; 32],
+}
+
+impl Default for HashChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HashChain {
+    pub fn new() -> Self {
+        Self { current: [0; 32] }
+    }
+
+    pub fn append(&mut self, record: &OperationRecord) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.current);
+        hasher.update(serialize_event(record).as_bytes());
+        self.current.copy_from_slice(&hasher.finalize());
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.current.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Amounts are normalized so `1.0` and `1.00` hash identically.
+fn serialize_event(record: &OperationRecord) -> String {
+    let mut event = format!(
+        "{:?},{},{},{}",
+        record.r#type,
+        record.client,
+        record.tx,
+        record
+            .amount
+            .map(|amt| amt.normalize().to_string())
+            .unwrap_or_default()
+    );
+    if let Some(target) = record.target {
+        event.push_str(&format!(",{}", target));
+    }
+    if let Some(parts) = &record.parts {
+        for (tx, amount) in &parts.0 {
+            event.push_str(&format!(",{}:{}", tx, amount.normalize()));
+        }
+    }
+    event
+}
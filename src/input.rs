@@ -0,0 +1,249 @@
+use anyhow::{Context, Result};
+use csv::Reader;
+use flate2::read::MultiGzDecoder;
+use payments_engine::engine::{self, Engine, Observer, Validation};
+use std::io::{self, BufRead, BufReader, Read};
+
+use crate::cli::Options;
+
+/// Builds the csv reader used for transaction input, per `engine::reader_builder`.
+/// `--read-buffer-bytes` overrides the csv crate's default buffer capacity and
+/// `--delimiter` the comma.
+pub fn csv_reader<R: Read>(source: R, options: &Options) -> Reader<R> {
+    let mut builder = engine::reader_builder();
+    if let Some(delimiter) = options.delimiter {
+        builder.delimiter(delimiter);
+    }
+    if let Some(capacity) = options.read_buffer_bytes {
+        builder.buffer_capacity(capacity);
+    }
+    builder.from_reader(source)
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Feeds one CSV source through `engine`, applying the per-source input options
+/// (`--max-line-bytes`). Gzipped input is decompressed first. `observer` is called
+/// after every applied operation, as in `Engine::process_observed`.
+pub fn process_source(
+    engine: &mut Engine,
+    source: impl Read,
+    options: &Options,
+    observer: &mut Observer<'_>,
+) -> Result<()> {
+    let source = decompress(source)?;
+    let source: Box<dyn Read> = match options.max_line_bytes {
+        Some(limit) => Box::new(LineLengthGuard::new(source, limit)),
+        None => Box::new(source),
+    };
+    engine.process_observed(&mut csv_reader(source, options), observer)
+}
+
+/// Parses one CSV source for `--validate-only`, prepared as in `process_source`.
+pub fn validate_source(
+    engine: &mut Engine,
+    source: impl Read,
+    options: &Options,
+) -> Result<Validation> {
+    let source = decompress(source)?;
+    let source: Box<dyn Read> = match options.max_line_bytes {
+        Some(limit) => Box::new(LineLengthGuard::new(source, limit)),
+        None => Box::new(source),
+    };
+    engine.validate(&mut csv_reader(source, options))
+}
+
+/// Wraps `source` in a gzip decoder if it starts with the gzip magic bytes, so
+/// detection does not depend on the file name (or there being one).
+fn decompress<'a>(source: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+    let mut source = BufReader::new(source);
+    let head = source.fill_buf().context("Failed to read input")?;
+    if head.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(MultiGzDecoder::new(source)))
+    } else {
+        Ok(Box::new(source))
+    }
+}
+
+pub fn is_zip(path: &str) -> bool {
+    path.to_lowercase().ends_with(".zip")
+}
+
+/// Processes every `.csv` member of a zip archive, in name order, as one ledger.
+///
+/// All members share `engine`, so a dispute in one file can refer to a deposit in an
+/// earlier one.
+#[cfg(feature = "zip")]
+pub fn process_zip(
+    engine: &mut Engine,
+    file: std::fs::File,
+    options: &Options,
+    observer: &mut Observer<'_>,
+) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(file).context("Failed to open zip archive")?;
+    let mut names = archive
+        .file_names()
+        .map(|name| name.map(String::from))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to list zip archive")?;
+    names.retain(|name| name.to_lowercase().ends_with(".csv"));
+    names.sort();
+    for name in names {
+        let member = archive
+            .by_name(&name)
+            .with_context(|| format!("Failed to read archive member {}", name))?;
+        process_source(engine, member, options, observer)
+            .with_context(|| format!("In {}", name))?;
+    }
+    Ok(())
+}
+
+/// Wraps a reader and fails once any line grows past `max_line_bytes`.
+///
+/// The csv reader buffers a whole record before yielding it, so a corrupt feed
+/// (e.g. an unterminated quote) could otherwise make it hold an arbitrarily large
+/// "line" in memory. Only bytes since the last `\n` are counted.
+pub struct LineLengthGuard<R> {
+    inner: R,
+    max_line_bytes: usize,
+    current_line_bytes: usize,
+    exceeded: bool,
+}
+
+impl<R: Read> LineLengthGuard<R> {
+    pub fn new(inner: R, max_line_bytes: usize) -> Self {
+        Self {
+            inner,
+            max_line_bytes,
+            current_line_bytes: 0,
+            exceeded: false,
+        }
+    }
+}
+
+impl<R: Read> Read for LineLengthGuard<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.exceeded {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Input line exceeds maximum length of {} bytes",
+                    self.max_line_bytes
+                ),
+            ));
+        }
+
+        let n = self.inner.read(buf)?;
+        for (i, &byte) in buf[..n].iter().enumerate() {
+            if byte == b'\n' {
+                self.current_line_bytes = 0;
+                continue;
+            }
+            self.current_line_bytes += 1;
+            if self.current_line_bytes > self.max_line_bytes {
+                // Hand back what precedes the offending line so earlier records
+                // still parse, then fail on the next read.
+                self.exceeded = true;
+                return if i == 0 { self.read(buf) } else { Ok(i) };
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::ReaderBuilder;
+    use payments_engine::config::EngineConfig;
+    use payments_engine::engine::process_transactions;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_line_guard_allows_normal_lines() {
+        let data = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let guard = LineLengthGuard::new(Cursor::new(data), 32);
+        let mut rdr = ReaderBuilder::new().flexible(true).from_reader(guard);
+        let (balances, _) =
+            process_transactions::<Decimal>(&mut rdr, &EngineConfig::default()).unwrap();
+        assert_eq!(balances.len(), 1);
+    }
+
+    #[test]
+    fn test_line_guard_rejects_giant_line() {
+        let data = format!(
+            "type,client,tx,amount\ndeposit,1,1,\"{}\n",
+            "9".repeat(1_000_000)
+        );
+        let guard = LineLengthGuard::new(Cursor::new(data), 1024);
+        let mut rdr = ReaderBuilder::new().flexible(true).from_reader(guard);
+        let err = process_transactions::<Decimal>(&mut rdr, &EngineConfig::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("maximum length of 1024 bytes"));
+    }
+
+    #[test]
+    fn test_buffer_size_does_not_change_results() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndeposit, 2, 2, 3.5\n\
+                    withdrawal, 1, 3, 4.25\ndispute, 2, 2\ndeposit, 1, 4, 0.0001\n";
+        let run = |capacity| {
+            let options = Options {
+                read_buffer_bytes: Some(capacity),
+                ..Options::default()
+            };
+            let mut rdr = csv_reader(Cursor::new(data), &options);
+            process_transactions::<Decimal>(&mut rdr, &EngineConfig::default())
+                .unwrap()
+                .0
+        };
+
+        let small = run(8);
+        let large = run(1 << 20);
+        assert_eq!(small.len(), 2);
+        assert_eq!(small, large);
+    }
+
+    #[test]
+    fn test_bom_and_crlf_input() -> Result<()> {
+        let data = "\u{feff}type,client,tx,amount\r\ndeposit,1,1,2.0\r\n\
+                    withdrawal,1,2,0.5\r\ndispute,1,1\r\n";
+        let options = Options {
+            max_line_bytes: Some(64),
+            ..Options::default()
+        };
+        let mut engine = Engine::new(EngineConfig::default());
+        process_source(&mut engine, Cursor::new(data), &options, &mut |_, _, _| {})?;
+        assert!(engine.skipped.is_empty());
+        assert_eq!(engine.client_balances[&1].available, dec!(-0.5));
+        assert_eq!(engine.client_balances[&1].held, dec!(2.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gzip_detected_by_magic_bytes() -> Result<()> {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let data = "type,client,tx,amount\ndeposit,1,1,2.5\nwithdrawal,1,2,1.0\n";
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data.as_bytes())?;
+        let gzipped = encoder.finish()?;
+
+        let run = |bytes: &[u8]| -> Result<Engine> {
+            let mut engine = Engine::new(EngineConfig::default());
+            process_source(
+                &mut engine,
+                Cursor::new(bytes.to_vec()),
+                &Options::default(),
+                &mut |_, _, _| {},
+            )?;
+            Ok(engine)
+        };
+        let plain = run(data.as_bytes())?;
+        let decompressed = run(&gzipped)?;
+        assert_eq!(decompressed.client_balances, plain.client_balances);
+        assert_eq!(decompressed.client_balances[&1].available, dec!(1.5));
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! Cross-check of final balances against independently tracked money movements.
+
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+use crate::models::ClientBalance;
+
+/// Per-client totals of every applied operation that changes `available + held`,
+/// tracked under `EngineConfig::ledger_stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LedgerStats {
+    /// Deposits and bonuses.
+    pub credited: Decimal,
+    /// Withdrawals, including their fees.
+    pub debited: Decimal,
+    /// Amounts removed by chargebacks of deposits.
+    pub charged_back: Decimal,
+    /// Amounts returned by chargebacks of withdrawals.
+    pub reversed: Decimal,
+    /// Held on disputed withdrawals that have not settled yet.
+    pub withdrawal_holds: Decimal,
+}
+
+impl LedgerStats {
+    /// What `available + held` should be after these operations.
+    pub fn expected_total(&self) -> Decimal {
+        self.credited - self.debited - self.charged_back + self.reversed + self.withdrawal_holds
+    }
+}
+
+/// Fails, listing every offending client, if any client's `available + held` differs
+/// from the total its `LedgerStats` account for. A client with no stats is expected
+/// to hold nothing.
+pub fn verify_invariants(
+    balances: &HashMap<u16, ClientBalance>,
+    ledger_stats: &HashMap<u16, LedgerStats>,
+) -> Result<()> {
+    let mut mismatches: Vec<(u16, Decimal, Decimal)> = balances
+        .iter()
+        .filter_map(|(&client, balance)| {
+            let expected = ledger_stats
+                .get(&client)
+                .map_or(Decimal::ZERO, LedgerStats::expected_total);
+            (balance.total() != expected).then_some((client, balance.total(), expected))
+        })
+        .collect();
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    mismatches.sort_unstable_by_key(|&(client, _, _)| client);
+    let details: Vec<String> = mismatches
+        .iter()
+        .map(|(client, total, expected)| {
+            format!("client {}: total {}, expected {}", client, total, expected)
+        })
+        .collect();
+    Err(anyhow!(
+        "Ledger invariant violated for {} client(s): {}",
+        mismatches.len(),
+        details.join("; ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DisputeHoldPolicy, EngineConfig, WithdrawalFee};
+    use crate::engine::{reader_builder, Engine};
+    use rust_decimal_macros::dec;
+
+    fn run(data: &str, config: EngineConfig) -> Result<Engine> {
+        let mut engine = Engine::new(EngineConfig {
+            ledger_stats: true,
+            ..config
+        });
+        engine.process(&mut reader_builder().from_reader(data.as_bytes()))?;
+        Ok(engine)
+    }
+
+    #[test]
+    fn test_invariants_hold_and_catch_corruption() -> Result<()> {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,10\n\
+                    withdrawal,1,2,4\n\
+                    dispute,1,1,\n\
+                    chargeback,1,1,\n\
+                    deposit,2,3,5\n\
+                    withdrawal,2,4,2\n\
+                    dispute,2,4,\n\
+                    deposit,3,5,8\n\
+                    withdrawal,3,6,3\n\
+                    dispute,3,6,\n\
+                    chargeback,3,6,\n\
+                    bonus,3,7,1\n";
+        let config = EngineConfig {
+            withdrawal_fee: WithdrawalFee::Flat(dec!(0.5)),
+            dispute_hold_policy: DisputeHoldPolicy::HoldAvailableOnly,
+            ..EngineConfig::default()
+        };
+        let mut engine = run(data, config)?;
+        let stats = engine.ledger_stats.clone().unwrap();
+        verify_invariants(&engine.client_balances, &stats)?;
+
+        engine.client_balances.get_mut(&2).unwrap().available += dec!(0.0001);
+        let err = verify_invariants(&engine.client_balances, &stats).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Ledger invariant violated for 1 client(s): client 2: total 4.5001, expected 4.5"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! Transaction processing as a library: feed a transactions CSV to [`run`] (or drive
+//! an [`Engine`] directly) and get the resulting client balances back.
+//!
+//! The `payments_engine` binary is a thin CLI over these modules.
+
+use anyhow::Result;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+
+mod amount;
+pub mod checkpoint;
+pub mod client_id;
+pub mod config;
+pub mod engine;
+pub mod hash_chain;
+pub mod invariants;
+pub mod merge;
+pub mod models;
+pub mod output;
+pub mod stats;
+
+pub use config::EngineConfig;
+pub use engine::Engine;
+pub use models::{ClientBalance, SkipReason, SkippedRecord};
+
+/// Final balances of a run together with summary counts over its input.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LedgerSnapshot {
+    pub balances: HashMap<u16, ClientBalance>,
+    pub records_processed: u64,
+    /// Records that were rejected or had no effect (e.g. a dispute on an unknown tx).
+    pub records_skipped: u64,
+    pub disputes_opened: u64,
+    /// The skipped records themselves, in input order.
+    pub skipped: Vec<SkippedRecord>,
+}
+
+impl LedgerSnapshot {
+    fn from_engine(engine: Engine) -> Self {
+        Self {
+            records_processed: engine.counts.records_processed,
+            records_skipped: engine.counts.records_skipped,
+            disputes_opened: engine.counts.disputes_opened,
+            balances: engine.client_balances,
+            skipped: engine.skipped,
+        }
+    }
+
+    pub fn balance(&self, client: u16) -> Option<&ClientBalance> {
+        self.balances.get(&client)
+    }
+
+    /// `available + held` for `client`.
+    pub fn total(&self, client: u16) -> Option<Decimal> {
+        self.balance(client).map(ClientBalance::total)
+    }
+
+    /// Clients frozen by a chargeback (or a `freeze` row), in ascending order.
+    pub fn locked_clients(&self) -> Vec<u16> {
+        let mut locked: Vec<u16> = self
+            .balances
+            .iter()
+            .filter(|(_, balance)| balance.locked)
+            .map(|(&client, _)| client)
+            .collect();
+        locked.sort_unstable();
+        locked
+    }
+
+    /// Balances in ascending client order.
+    pub fn iter_sorted(&self) -> impl Iterator<Item = (u16, &ClientBalance)> + '_ {
+        let mut clients: Vec<u16> = self.balances.keys().copied().collect();
+        clients.sort_unstable();
+        clients
+            .into_iter()
+            .map(move |client| (client, &self.balances[&client]))
+    }
+}
+
+/// Processes a transactions CSV with the default configuration.
+pub fn run(reader: impl Read) -> Result<LedgerSnapshot> {
+    run_with_config(reader, EngineConfig::default())
+}
+
+/// Processes a transactions CSV under `config`.
+pub fn run_with_config(reader: impl Read, config: EngineConfig) -> Result<LedgerSnapshot> {
+    let mut engine = Engine::new(config);
+    engine.process(&mut engine::reader_builder().from_reader(reader))?;
+    Ok(LedgerSnapshot::from_engine(engine))
+}
+
+/// Processes a transactions CSV held in memory and returns the balances CSV the
+/// CLI would write. Touches no files or environment, so it is the entry point for
+/// builds such as `wasm32-unknown-unknown` that have neither.
+pub fn process_csv_string(input: &str) -> Result<String> {
+    let snapshot = run(Cursor::new(input))?;
+    let mut out = Vec::new();
+    output::write_csv(
+        &snapshot.balances,
+        &output::OutputOptions::default(),
+        &mut out,
+    )?;
+    Ok(String::from_utf8(out)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_run_counts() -> Result<()> {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 10.0\n\
+                     withdrawal, 1, 2, 25.0\n\
+                     dispute, 1, 1,\n\
+                     dispute, 1, 99,\n\
+                     resolve, 1, 1,\n";
+        let snapshot = run(input.as_bytes())?;
+        assert_eq!(snapshot.records_processed, 5);
+        assert_eq!(snapshot.records_skipped, 2);
+        assert_eq!(snapshot.disputes_opened, 1);
+        let reasons: Vec<SkipReason> = snapshot.skipped.iter().map(|s| s.reason).collect();
+        assert_eq!(
+            reasons,
+            [SkipReason::InsufficientFunds, SkipReason::UnknownTx]
+        );
+        assert_eq!(snapshot.balances[&1].available, dec!(10.0));
+        assert_eq!(snapshot.balances[&1].held, dec!(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_accessors() -> Result<()> {
+        let input = "type,client,tx,amount\n\
+                     deposit,3,1,4.0\n\
+                     deposit,1,2,10.0\n\
+                     deposit,2,3,1.5\n\
+                     dispute,1,2,\n\
+                     deposit,1,4,2.0\n\
+                     dispute,2,3,\n\
+                     chargeback,2,3,\n";
+        let snapshot = run(input.as_bytes())?;
+        assert_eq!(snapshot.balance(1).map(|b| b.held), Some(dec!(10.0)));
+        assert_eq!(snapshot.balance(9), None);
+        assert_eq!(snapshot.total(1), Some(dec!(12.0)));
+        assert_eq!(snapshot.total(9), None);
+        assert_eq!(snapshot.locked_clients(), [2]);
+        let order: Vec<u16> = snapshot.iter_sorted().map(|(client, _)| client).collect();
+        assert_eq!(order, [1, 2, 3]);
+
+        let empty = run("type,client,tx,amount\n".as_bytes())?;
+        assert_eq!(empty.balance(1), None);
+        assert_eq!(empty.total(1), None);
+        assert!(empty.locked_clients().is_empty());
+        assert_eq!(empty.iter_sorted().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_csv_string() -> Result<()> {
+        let output = process_csv_string(include_str!("../examples/sample_input.csv"))?;
+        assert_eq!(output, include_str!("../examples/expected_output.csv"));
+        assert!(process_csv_string("type,client,tx,amount\ndeposit,x,1,1.0\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_credit_and_debit_move_balances() -> Result<()> {
+        let input = "type,client,tx,amount\n\
+                     CREDIT,1,1,10.0\n\
+                     Debit,1,2,2.5\n\
+                     debit,1,3,20.0\n";
+        let snapshot = run(input.as_bytes())?;
+        assert_eq!(snapshot.balances[&1].available, dec!(7.5));
+        assert_eq!(snapshot.skipped[0].reason, SkipReason::InsufficientFunds);
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Advisory lock held for the duration of a run.
+///
+/// Acquired by exclusively creating the lock file, so a second instance pointed at
+/// the same path is refused until the first one drops its `LockFile` (which removes
+/// the file). A crashed run leaves the file behind and must be cleaned up manually.
+#[derive(Debug)]
+pub struct LockFile {
+    path: PathBuf,
+}
+
+impl LockFile {
+    pub fn acquire(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                return Err(anyhow!(
+                    "Another instance is already running (lock file {} exists)",
+                    path.display()
+                ));
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to create lock file {}", path.display()))
+            }
+        };
+        writeln!(file, "{}", std::process::id()).context("Failed to write lock file")?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_second_acquire_is_refused() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("engine.lock");
+
+        let _held = LockFile::acquire(&path)?;
+        let err = LockFile::acquire(&path).unwrap_err();
+        assert!(err.to_string().contains("already running"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_released_on_drop() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("engine.lock");
+
+        drop(LockFile::acquire(&path)?);
+        assert!(!path.exists());
+        let _again = LockFile::acquire(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::env;
+
+/// Minimal stderr logger, installed only when `RUST_LOG` names a level
+/// (`error`, `warn`, `info`, `debug`, `trace` or `off`).
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Wires up `StderrLogger` if `RUST_LOG` is set. Without it, log calls are no-ops.
+pub fn init_from_env() {
+    let Some(level) = env::var("RUST_LOG")
+        .ok()
+        .and_then(|value| value.parse::<LevelFilter>().ok())
+    else {
+        return;
+    };
+    // Only fails if a logger is already installed, in which case that one stays.
+    if log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(level);
+    }
+}
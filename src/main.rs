@@ -1,52 +1,705 @@
+// The CLI is built on files, sockets and the environment. A browser has none of
+// these, so wasm builds get no binary entry point and call the library's
+// `process_csv_string` instead.
+#![cfg_attr(target_arch = "wasm32", no_main, allow(dead_code, unused_imports))]
+
 use anyhow::{Context, Result};
-use csv::{ReaderBuilder, Writer};
-use engine::process_transactions;
-use rust_decimal::Decimal;
+use audit::AuditLog;
+use cli::{Command, Options};
+use lockfile::LockFile;
+use payments_engine::client_id::ClientInterner;
+use payments_engine::engine::{Engine, Observer, Progress};
+use payments_engine::models::{ClientBalance, OperationRecord};
+use payments_engine::output::{
+    self, BalanceSummary, CsvWriter, JsonWriter, NdjsonWriter, OutputFormat, OutputOptions,
+    OutputWriter, SummaryTarget,
+};
+use payments_engine::{invariants, merge};
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
-use std::io::{self};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::TcpListener;
 
-mod engine;
-mod models;
+mod audit;
+mod cli;
+mod input;
+mod lockfile;
+mod logger;
+mod server;
 
+#[cfg(not(target_arch = "wasm32"))]
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        return Err(anyhow::anyhow!("Usage: cargo run -- <input.csv>"));
-    }
-
-    let file = File::open(&args[1]).context("Failed to open input file")?;
-    let mut rdr = ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .flexible(true)
-        .from_reader(file);
-
-    let client_balances = process_transactions(&mut rdr)?;
-
-    let mut wtr = Writer::from_writer(io::stdout());
-    wtr.write_record(&["client", "available", "held", "total", "locked"])
-        .context("Failed to write header")?;
-
-    let mut client_ids: Vec<u16> = client_balances.keys().cloned().collect();
-    client_ids.sort();
-
-    for id in client_ids {
-        let balance = client_balances.get(&id).unwrap();
-        let total = balance.available + balance.held;
-        wtr.write_record(&[
-            id.to_string(),
-            format_decimal(balance.available),
-            format_decimal(balance.held),
-            format_decimal(total),
-            if balance.locked { "true" } else { "false" }.to_string(),
-        ])
-        .context("Failed to write record")?;
+    logger::init_from_env();
+    let options = Options::parse(env::args().skip(1))?;
+    run(&options, &mut io::stdin().lock(), &mut io::stdout().lock())
+}
+
+/// Runs the command in `options`. `stdin` is read for a `-` input, or when no input
+/// is given.
+fn run(options: &Options, stdin: &mut dyn Read, stdout: &mut dyn Write) -> Result<()> {
+    let _lock = options
+        .lock_file
+        .as_ref()
+        .map(LockFile::acquire)
+        .transpose()?;
+    if let Command::MergeOutputs(paths) = &options.command {
+        return run_merge(paths, options, stdout);
+    }
+    if let Command::Serve(addr) = &options.command {
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+        log::info!("listening on {}", addr);
+        let mut engine = Engine::new(options.engine.clone());
+        return server::serve(listener, &mut engine, &presentation(options));
+    }
+
+    // Every file is opened up front, so a bad path fails before anything is processed.
+    let files = options
+        .inputs
+        .iter()
+        .map(|path| {
+            (path != cli::STDIN)
+                .then(|| open_input(path, options))
+                .transpose()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if options.validate_only {
+        return run_validation(options, files, stdin, stdout);
     }
+    let mut engine = Engine::new(options.engine.clone());
+    if options.progress {
+        engine.progress = Some(Progress::new(cli::PROGRESS_EVERY, |rows, elapsed| {
+            eprintln!("processed {} rows in {:.1}s", rows, elapsed.as_secs_f64());
+        }));
+    }
+    let precision = options.precision.unwrap_or(output::DEFAULT_PRECISION);
+    let mut audit = options
+        .audit
+        .as_deref()
+        .map(|path| AuditLog::create(path, precision))
+        .transpose()?;
+    let mut observer = |record: &OperationRecord, balance: &ClientBalance, name: Option<&str>| {
+        if let Some(audit) = &mut audit {
+            audit.record(record, balance, name);
+        }
+    };
+    let processed = options
+        .inputs
+        .iter()
+        .zip(files)
+        .try_for_each(|(path, file)| {
+            let processed = match file {
+                Some(file) => process_file(&mut engine, path, file, options, &mut observer),
+                None => input::process_source(&mut engine, &mut *stdin, options, &mut observer),
+            };
+            if options.inputs.len() > 1 {
+                processed.with_context(|| format!("In {}", path))
+            } else {
+                processed
+            }
+        });
+    if let Some(audit) = audit {
+        audit.finish()?;
+    }
+    if processed.is_err() && !options.emit_partial_on_error {
+        return processed;
+    }
+    if options.auto_resolve_open && processed.is_ok() {
+        let resolved = engine.resolve_open_disputes()?;
+        eprintln!("auto-resolved {} open disputes", resolved);
+    }
+    if let Some(stats) = &engine.ledger_stats {
+        invariants::verify_invariants(&engine.client_balances, stats)?;
+    }
+    let client_balances = &engine.client_balances;
+    let output_options = OutputOptions {
+        client_names: engine.client_names.clone(),
+        anonymized_ids: options
+            .anonymize
+            .then(|| output::anonymize(&engine.first_seen))
+            .transpose()?,
+        client_flows: engine.client_flows.clone(),
+        client_hashes: engine.client_hashes.clone(),
+        ..presentation(options)
+    };
+    if let Some(path) = &options.anonymize_map {
+        let file = File::create(path).context("Failed to create anonymization map")?;
+        output::write_anonymization_map(&engine.first_seen, &output_options, file)?;
+    }
+
+    let client = |id| client_label(engine.client_names.as_ref(), id);
+    if options.report_skipped {
+        for skipped in &engine.skipped {
+            eprintln!(
+                "skipped {:?}: client {} tx {} ({:?})",
+                skipped.r#type,
+                client(skipped.client),
+                skipped.tx,
+                skipped.reason
+            );
+        }
+    }
+
+    for shortfall in &engine.dispute_shortfalls {
+        eprintln!(
+            "dispute shortfall: client {} tx {} left {} unheld",
+            client(shortfall.client),
+            shortfall.tx,
+            shortfall.shortfall
+        );
+    }
+    for shortfall in &engine.withdrawal_shortfalls {
+        eprintln!(
+            "withdrawal shortfall: client {} tx {} left {} unfilled",
+            client(shortfall.client),
+            shortfall.tx,
+            shortfall.shortfall
+        );
+    }
+
+    if let Some(stats) = &engine.amount_stats {
+        eprintln!("deposit amounts: {}", stats.deposits);
+        eprintln!("withdrawal amounts: {}", stats.withdrawals);
+    }
+
+    if let Some(chain) = &engine.hash_chain {
+        eprintln!("hash chain: {}", chain.to_hex());
+        if let Some(path) = &options.hash_chain_file {
+            std::fs::write(path, format!("{}\n", chain.to_hex()))
+                .context("Failed to write hash chain file")?;
+        }
+    }
+
+    let written = write_balances(client_balances, &output_options, options, stdout);
+
+    // Under --emit-partial-on-error the balances so far are written first, then the
+    // processing error still fails the run.
+    processed.and(written)
+}
+
+fn run_merge(paths: &[String], options: &Options, stdout: &mut dyn Write) -> Result<()> {
+    let ledgers = paths
+        .iter()
+        .map(|path| {
+            let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+            merge::read_balances(file).with_context(|| format!("In {}", path))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    write_balances(
+        &merge::merge_ledgers(ledgers)?,
+        &presentation(options),
+        options,
+        stdout,
+    )
+}
+
+/// `--validate-only`: parses every input, listing bad rows on stderr and a summary on
+/// `stdout`, without computing balances. Fails if any row is invalid.
+fn run_validation(
+    options: &Options,
+    files: Vec<Option<File>>,
+    stdin: &mut dyn Read,
+    stdout: &mut dyn Write,
+) -> Result<()> {
+    if options.inputs.iter().any(|path| input::is_zip(path)) {
+        return Err(anyhow::anyhow!(
+            "--validate-only does not support zip input"
+        ));
+    }
+    let mut engine = Engine::new(options.engine.clone());
+    let (mut rows, mut invalid) = (0, 0);
+    for (path, file) in options.inputs.iter().zip(files) {
+        let validation = match file {
+            Some(file) => input::validate_source(&mut engine, file, options),
+            None => input::validate_source(&mut engine, &mut *stdin, options),
+        }
+        .with_context(|| format!("In {}", path))?;
+        for error in &validation.errors {
+            eprintln!("{}:{}: {}", path, error.line, error.message);
+        }
+        rows += validation.rows;
+        invalid += validation.errors.len();
+    }
+    writeln!(stdout, "{} rows checked, {} invalid", rows, invalid)
+        .context("Failed to write summary")?;
+    if invalid > 0 {
+        return Err(anyhow::anyhow!("{} invalid rows", invalid));
+    }
+    Ok(())
+}
+
+fn open_input(path: &str, options: &Options) -> Result<File> {
+    let file = File::open(path).with_context(|| format!("Failed to open input file {}", path))?;
+    if let Some(limit) = options.max_input_bytes {
+        // Pipes and other special files report no meaningful length; only regular
+        // files are checked.
+        let metadata = file.metadata().context("Failed to read input metadata")?;
+        if metadata.is_file() && metadata.len() > limit {
+            return Err(anyhow::anyhow!(
+                "Input file is {} bytes, over the --max-input-bytes limit of {}",
+                metadata.len(),
+                limit
+            ));
+        }
+    }
+    Ok(file)
+}
+
+fn process_file(
+    engine: &mut Engine,
+    path: &str,
+    file: File,
+    options: &Options,
+    observer: &mut Observer<'_>,
+) -> Result<()> {
+    if input::is_zip(path) {
+        #[cfg(feature = "zip")]
+        return input::process_zip(engine, file, options, observer);
+        #[cfg(not(feature = "zip"))]
+        return Err(anyhow::anyhow!(
+            "Zip input requires building with `--features zip`"
+        ));
+    }
+    input::process_source(engine, file, options, observer)
+}
+
+/// Output settings that come straight from the command line, independent of what
+/// the engine tracked.
+/// Client `id` as the input named it: its name under `--string-clients`.
+fn client_label(names: Option<&ClientInterner>, id: u16) -> String {
+    match names.and_then(|names| names.name(id)) {
+        Some(name) => name.to_string(),
+        None => id.to_string(),
+    }
+}
+
+fn presentation(options: &Options) -> OutputOptions {
+    OutputOptions {
+        truncate: options.truncate,
+        pad_client: options.pad_client,
+        sort_by: options.sort_by,
+        descending: options.descending,
+        group_by_locked: options.group_by_locked,
+        max_rows: options.max_output_rows,
+        columns: options.columns.clone(),
+        precision: options.precision,
+        delimiter: options.delimiter,
+        with_stats: options.with_stats,
+        verbose: options.verbose,
+        report_fees: options.report_fees,
+        ..OutputOptions::default()
+    }
+}
+
+fn write_balances(
+    client_balances: &HashMap<u16, ClientBalance>,
+    output_options: &OutputOptions,
+    options: &Options,
+    stdout: &mut dyn Write,
+) -> Result<()> {
+    if let Some(note) = output::truncation_note(client_balances.len(), output_options) {
+        eprintln!("{}", note);
+    }
+
+    // Written first so a failure here surfaces before the main output is replaced.
+    if let Some(path) = &options.json_output {
+        let file = File::create(path).context("Failed to create JSON output file")?;
+        output::write_json(client_balances, output_options, BufWriter::new(file))?;
+    }
+
+    if let Some(SummaryTarget::Stderr) = options.summary {
+        for line in BalanceSummary::of(client_balances).lines(output_options) {
+            eprintln!("{}", line);
+        }
+    }
+
+    let out_file = options
+        .output
+        .as_ref()
+        .map(File::create)
+        .transpose()
+        .context("Failed to create output file")?;
+
+    let mut out: Box<dyn Write + '_> = match &out_file {
+        Some(file) => Box::new(BufWriter::new(file)),
+        None => Box::new(stdout),
+    };
 
-    wtr.flush().context("Failed to flush output")?;
+    let writer: Box<dyn OutputWriter> = match options.format {
+        OutputFormat::Csv => Box::new(CsvWriter),
+        OutputFormat::Ndjson => Box::new(NdjsonWriter),
+        OutputFormat::Json => Box::new(JsonWriter),
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => {
+            drop(out);
+            return output::write_parquet(client_balances, output_options, out_file.unwrap());
+        }
+        #[cfg(not(feature = "parquet"))]
+        OutputFormat::Parquet => {
+            return Err(anyhow::anyhow!(
+                "Parquet output requires building with `--features parquet`"
+            ))
+        }
+    };
+    writer.write(client_balances, output_options, &mut out)?;
+    if let Some(SummaryTarget::Footer) = options.summary {
+        for line in BalanceSummary::of(client_balances).lines(output_options) {
+            writeln!(out, "{}", line)?;
+        }
+    }
     Ok(())
 }
 
-fn format_decimal(value: Decimal) -> String {
-    format!("{:.4}", value.round_dp(4))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn input_file(contents: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    fn options_for(file: &NamedTempFile) -> Options {
+        Options {
+            inputs: vec![file.path().to_str().unwrap().to_string()],
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn test_partial_output_on_error() {
+        let file = input_file(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\ndeposit,x,3,1.0\n",
+        );
+
+        let mut out = Vec::new();
+        assert!(run(&options_for(&file), &mut io::empty(), &mut out).is_err());
+        assert!(out.is_empty());
+
+        let options = Options {
+            emit_partial_on_error: true,
+            ..options_for(&file)
+        };
+        let mut out = Vec::new();
+        let err = run(&options, &mut io::empty(), &mut out).unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to deserialize record"));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n\
+             1,5.0000,0.0000,5.0000,false\n\
+             2,3.0000,0.0000,3.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_anonymize_by_first_appearance() {
+        let file = input_file(
+            "type,client,tx,amount\ndeposit,9,1,5.0\ndeposit,3,2,3.0\nwithdrawal,9,3,1.0\n",
+        );
+        let map = NamedTempFile::new().unwrap();
+        let options = Options {
+            anonymize: true,
+            anonymize_map: Some(map.path().to_str().unwrap().to_string()),
+            ..options_for(&file)
+        };
+
+        let mut out = Vec::new();
+        run(&options, &mut io::empty(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n\
+             1,4.0000,0.0000,4.0000,false\n\
+             2,3.0000,0.0000,3.0000,false\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(map.path()).unwrap(),
+            "anonymized,client\n1,9\n2,3\n"
+        );
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn test_zip_members_processed_in_name_order() {
+        use zip::write::{SimpleFileOptions, ZipWriter};
+
+        let archive = tempfile::Builder::new().suffix(".zip").tempfile().unwrap();
+        let mut zip = ZipWriter::new(archive.reopen().unwrap());
+        // Written out of order: `b.csv` disputes a deposit made in `a.csv`.
+        for (name, contents) in [
+            (
+                "b.csv",
+                "type,client,tx,amount\ndispute,1,1\ndeposit,2,3,4.0\n",
+            ),
+            (
+                "a.csv",
+                "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,1.0\n",
+            ),
+            ("notes.txt", "not a ledger"),
+        ] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let options = Options {
+            inputs: vec![archive.path().to_str().unwrap().to_string()],
+            ..Options::default()
+        };
+        let mut out = Vec::new();
+        run(&options, &mut io::empty(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n\
+             1,-1.0000,5.0000,4.0000,false\n\
+             2,4.0000,0.0000,4.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_and_json_outputs_agree() {
+        let file =
+            input_file("type,client,tx,amount\ndeposit,2,1,5.5\ndeposit,1,2,3.0\ndispute,2,1\n");
+        let csv_out = NamedTempFile::new().unwrap();
+        let json_out = NamedTempFile::new().unwrap();
+        let options = Options {
+            output: Some(csv_out.path().to_str().unwrap().to_string()),
+            json_output: Some(json_out.path().to_str().unwrap().to_string()),
+            ..options_for(&file)
+        };
+        let mut stdout = Vec::new();
+        run(&options, &mut io::empty(), &mut stdout).unwrap();
+        assert!(stdout.is_empty());
+
+        let json: Vec<serde_json::Value> =
+            serde_json::from_str(&std::fs::read_to_string(json_out.path()).unwrap()).unwrap();
+        let csv = std::fs::read_to_string(csv_out.path()).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(rows.len(), json.len());
+        for (row, object) in rows.iter().zip(&json) {
+            let expected = format!(
+                "{},{},{},{},{}",
+                object["client"],
+                object["available"].as_str().unwrap(),
+                object["held"].as_str().unwrap(),
+                object["total"].as_str().unwrap(),
+                object["locked"]
+            );
+            assert_eq!(*row, expected);
+        }
+        assert_eq!(rows[1], "2,0.0000,5.5000,5.5000,false");
+    }
+
+    #[test]
+    fn test_output_file_replaces_stdout() {
+        let file = input_file("type,client,tx,amount\ndeposit,1,1,5.0\n");
+        let out_file = input_file("stale contents that are longer than the balances\n");
+        let path = |file: &NamedTempFile| file.path().to_str().unwrap().to_string();
+        let options = Options::parse([path(&file), "-o".to_string(), path(&out_file)]).unwrap();
+
+        let mut stdout = Vec::new();
+        run(&options, &mut io::empty(), &mut stdout).unwrap();
+        assert!(stdout.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(out_file.path()).unwrap(),
+            "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_summary_footer() {
+        let file = input_file(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\ndeposit,3,3,1.25\n\
+             dispute,2,2,\ndispute,3,3,\nchargeback,3,3,\n",
+        );
+        let path = file.path().to_str().unwrap().to_string();
+        let options =
+            Options::parse(["--summary".to_string(), "footer".to_string(), path]).unwrap();
+
+        let mut out = Vec::new();
+        run(&options, &mut io::empty(), &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.ends_with(
+            "3,0.0000,0.0000,0.0000,true\n\
+             # clients: 3\n\
+             # locked: 1\n\
+             # available: 5.0000\n\
+             # held: 3.0000\n"
+        ));
+        // The footer does not stop the output being merged later.
+        assert_eq!(merge::read_balances(text.as_bytes()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_max_input_bytes() {
+        let file = input_file("type,client,tx,amount\ndeposit,1,1,5.0\n");
+        let limited = |limit| Options {
+            max_input_bytes: Some(limit),
+            ..options_for(&file)
+        };
+
+        let mut out = Vec::new();
+        let err = run(&limited(16), &mut io::empty(), &mut out).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("over the --max-input-bytes limit of 16"));
+        assert!(out.is_empty());
+
+        run(&limited(1024), &mut io::empty(), &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("1,5.0000"));
+    }
+
+    #[test]
+    fn test_tab_delimiter() {
+        let file = input_file("type\tclient\ttx\tamount\ndeposit\t2\t1\t5.0\ndeposit\t1\t2\t1.5\n");
+        let options = Options::parse([
+            "--delimiter".to_string(),
+            "tab".to_string(),
+            file.path().to_str().unwrap().to_string(),
+        ])
+        .unwrap();
+        let mut out = Vec::new();
+        run(&options, &mut io::empty(), &mut out).unwrap();
+        assert!(out.ends_with(b"\n"));
+
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_reader(out.as_slice());
+        assert_eq!(
+            rdr.headers().unwrap(),
+            vec!["client", "available", "held", "total", "locked"]
+        );
+        let rows: Vec<csv::StringRecord> = rdr.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0], vec!["1", "1.5000", "0.0000", "1.5000", "false"]);
+        assert_eq!(rows[1], vec!["2", "5.0000", "0.0000", "5.0000", "false"]);
+    }
+
+    #[test]
+    fn test_audit_trail() {
+        let file = input_file(
+            "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,9.0\ndeposit,2,3,1.5\n\
+             dispute,1,1,\nwithdrawal,2,4,0.5\n",
+        );
+        let audit = NamedTempFile::new().unwrap();
+        let path = |file: &NamedTempFile| file.path().to_str().unwrap().to_string();
+        let options = Options::parse(["--audit".to_string(), path(&audit), path(&file)]).unwrap();
+        let mut out = Vec::new();
+        run(&options, &mut io::empty(), &mut out).unwrap();
+
+        // The rejected withdrawal (tx 2) is not audited.
+        assert_eq!(
+            std::fs::read_to_string(audit.path()).unwrap(),
+            "seq,type,client,tx,amount,available,held,locked\n\
+             1,deposit,1,1,5.0000,5.0000,0.0000,false\n\
+             2,deposit,2,3,1.5000,1.5000,0.0000,false\n\
+             3,dispute,1,1,,0.0000,5.0000,false\n\
+             4,withdrawal,2,4,0.5000,1.0000,0.0000,false\n"
+        );
+        let plain = Options::parse([path(&file)]).unwrap();
+        let mut plain_out = Vec::new();
+        run(&plain, &mut io::empty(), &mut plain_out).unwrap();
+        assert_eq!(out, plain_out);
+    }
+
+    #[test]
+    fn test_audit_trail_names_string_clients() {
+        let file = input_file("type,client,tx,amount\ndeposit,zed,1,2.0\ndeposit,alice,2,1.0\n");
+        let audit = NamedTempFile::new().unwrap();
+        let path = |file: &NamedTempFile| file.path().to_str().unwrap().to_string();
+        let options = Options::parse([
+            "--string-clients".to_string(),
+            "--audit".to_string(),
+            path(&audit),
+            path(&file),
+        ])
+        .unwrap();
+        run(&options, &mut io::empty(), &mut Vec::new()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(audit.path()).unwrap(),
+            "seq,type,client,tx,amount,available,held,locked\n\
+             1,deposit,zed,1,2.0000,2.0000,0.0000,false\n\
+             2,deposit,alice,2,1.0000,1.0000,0.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_gzipped_input_file() {
+        use flate2::{write::GzEncoder, Compression};
+
+        let csv = "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,1.5\ndeposit,2,3,2.0\n";
+        let plain = input_file(csv);
+        let gzipped = tempfile::Builder::new()
+            .suffix(".csv.gz")
+            .tempfile()
+            .unwrap();
+        let mut encoder = GzEncoder::new(gzipped.reopen().unwrap(), Compression::default());
+        encoder.write_all(csv.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let output = |file: &NamedTempFile| {
+            let mut out = Vec::new();
+            run(&options_for(file), &mut io::empty(), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(output(&gzipped), output(&plain));
+        assert!(output(&gzipped).contains("1,3.5000,0.0000,3.5000,false"));
+    }
+
+    #[test]
+    fn test_multiple_inputs_share_one_ledger() {
+        let day1 = input_file("type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\n");
+        let day2 = input_file("type,client,tx,amount\ndispute,1,1,\nwithdrawal,2,3,1.0\n");
+        let path = |file: &NamedTempFile| file.path().to_str().unwrap().to_string();
+        let options = Options::parse([path(&day1), path(&day2)]).unwrap();
+
+        let mut out = Vec::new();
+        run(&options, &mut io::empty(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n\
+             1,0.0000,5.0000,5.0000,false\n\
+             2,2.0000,0.0000,2.0000,false\n"
+        );
+
+        // A missing file is reported before any input is processed.
+        let options = Options::parse([path(&day1), "missing.csv".to_string()]).unwrap();
+        let mut out = Vec::new();
+        let err = run(&options, &mut io::empty(), &mut out).unwrap_err();
+        assert!(err.to_string().contains("missing.csv"));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_validate_only_counts_bad_rows() {
+        let file = input_file("type,client,tx,amount\ndeposit,1,1,5.0\nrefund,1,2,1.0\n");
+        let options = Options {
+            validate_only: true,
+            ..options_for(&file)
+        };
+
+        let mut out = Vec::new();
+        let err = run(&options, &mut io::empty(), &mut out).unwrap_err();
+        assert_eq!(err.to_string(), "1 invalid rows");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2 rows checked, 1 invalid\n"
+        );
+    }
+
+    #[test]
+    fn test_reads_stdin_when_no_path_given() {
+        let options = Options::parse(Vec::new()).unwrap();
+        assert_eq!(options.inputs, [cli::STDIN]);
+
+        let mut stdin = io::Cursor::new("type,client,tx,amount\ndeposit,1,1,5.0\n");
+        let mut out = Vec::new();
+        run(&options, &mut stdin, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n"
+        );
+    }
 }
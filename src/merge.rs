@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Context, Result};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Read;
+
+use crate::models::ClientBalance;
+
+/// One row of a balances CSV as written by `output::write_csv`. `total` (and any
+/// extra columns such as `net_flow`) are ignored and recomputed on output.
+#[derive(Debug, Deserialize)]
+struct BalanceRecord {
+    client: u16,
+    available: Decimal,
+    held: Decimal,
+    locked: bool,
+}
+
+/// Parses a balances CSV produced by an earlier run back into `ClientBalance`s.
+/// `#` lines, such as a `--summary footer`, are skipped.
+pub fn read_balances(source: impl Read) -> Result<HashMap<u16, ClientBalance>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_reader(source);
+    let mut balances = HashMap::new();
+    for result in rdr.deserialize() {
+        let record: BalanceRecord = result.context("Failed to deserialize balance row")?;
+        let balance = ClientBalance {
+            available: record.available,
+            held: record.held,
+            locked: record.locked,
+            lock_reason: None,
+        };
+        if balances.insert(record.client, balance).is_some() {
+            return Err(anyhow!("Client {} appears more than once", record.client));
+        }
+    }
+    Ok(balances)
+}
+
+/// Combines per-shard ledgers: amounts are summed and an account is locked if it
+/// was locked in any shard. Fails, naming the client, if a sum (or the total of
+/// the summed `available` and `held`) overflows.
+pub fn merge_ledgers(
+    ledgers: impl IntoIterator<Item = HashMap<u16, ClientBalance>>,
+) -> Result<HashMap<u16, ClientBalance>> {
+    let mut merged: HashMap<u16, ClientBalance> = HashMap::new();
+    for ledger in ledgers {
+        for (client, balance) in ledger {
+            let entry = merged.entry(client).or_default();
+            let sums = entry
+                .available
+                .checked_add(balance.available)
+                .zip(entry.held.checked_add(balance.held))
+                .filter(|(available, held)| available.checked_add(*held).is_some());
+            let (available, held) =
+                sums.ok_or_else(|| anyhow!("Balance of client {} overflows when merged", client))?;
+            entry.available = available;
+            entry.held = held;
+            entry.locked |= balance.locked;
+            entry.lock_reason = entry.lock_reason.or(balance.lock_reason);
+        }
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_merge_two_outputs() -> Result<()> {
+        let first = "client,available,held,total,locked\n\
+                     1,10.0000,2.5000,12.5000,false\n\
+                     2,3.0000,0.0000,3.0000,true\n";
+        let second = "client,available,held,total,locked\n\
+                      1,-1.2500,0.0000,-1.2500,true\n\
+                      3,4.0000,1.0000,5.0000,false\n";
+        let merged = merge_ledgers([
+            read_balances(Cursor::new(first))?,
+            read_balances(Cursor::new(second))?,
+        ])?;
+
+        assert_eq!(merged.len(), 3);
+        assert_eq!(
+            merged[&1],
+            ClientBalance {
+                available: dec!(8.75),
+                held: dec!(2.5),
+                locked: true,
+                lock_reason: None,
+            }
+        );
+        assert!(merged[&2].locked);
+        assert!(!merged[&3].locked);
+        assert_eq!(merged[&3].held, dec!(1.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_overflow_is_an_error() {
+        let shard = |available| {
+            HashMap::from([(
+                4,
+                ClientBalance {
+                    available,
+                    ..ClientBalance::new()
+                },
+            )])
+        };
+        let err = merge_ledgers([shard(Decimal::MAX), shard(Decimal::MAX)]).unwrap_err();
+        assert_eq!(err.to_string(), "Balance of client 4 overflows when merged");
+        assert!(merge_ledgers([shard(Decimal::MAX), shard(dec!(-1))]).is_ok());
+    }
+
+    #[test]
+    fn test_read_balances_rejects_duplicate_client() {
+        let data = "client,available,held,total,locked\n1,1,0,1,false\n1,2,0,2,false\n";
+        assert!(read_balances(Cursor::new(data)).is_err());
+    }
+}
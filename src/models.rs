@@ -1,9 +1,14 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use rust_decimal::Decimal;
 use serde::{
     de::{self, Deserializer},
-    Deserialize,
+    ser::Serializer,
+    Deserialize, Serialize,
 };
+use std::fmt;
+use std::str::FromStr;
+
+use crate::output::{BalanceRow, OutputOptions};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum OperationType {
@@ -12,6 +17,34 @@ pub enum OperationType {
     Dispute,
     Resolve,
     Chargeback,
+    /// Moves an open dispute (and its hold) from `tx` to the deposit in `target`.
+    ReassignHold,
+    /// Promotional credit to `available`; never logged, so it cannot be disputed.
+    Bonus,
+    /// Replaces deposit `tx` with the deposits listed in `parts`.
+    Split,
+    /// Administrative lock of the client's account; `amount` is ignored.
+    Freeze,
+    /// Lifts a lock, whether from `freeze` or a chargeback; `amount` is ignored.
+    Unfreeze,
+}
+
+impl OperationType {
+    /// The canonical lowercase name, as written in the input's `type` column.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OperationType::Deposit => "deposit",
+            OperationType::Withdrawal => "withdrawal",
+            OperationType::Dispute => "dispute",
+            OperationType::Resolve => "resolve",
+            OperationType::Chargeback => "chargeback",
+            OperationType::ReassignHold => "reassign_hold",
+            OperationType::Bonus => "bonus",
+            OperationType::Split => "split",
+            OperationType::Freeze => "freeze",
+            OperationType::Unfreeze => "unfreeze",
+        }
+    }
 }
 
 impl<'de> Deserialize<'de> for OperationType {
@@ -21,39 +54,153 @@ impl<'de> Deserialize<'de> for OperationType {
     {
         let s = String::deserialize(deserializer)?;
         match s.to_lowercase().as_str() {
-            "deposit" => Ok(OperationType::Deposit),
-            "withdrawal" => Ok(OperationType::Withdrawal),
+            "deposit" | "credit" => Ok(OperationType::Deposit),
+            "withdrawal" | "debit" => Ok(OperationType::Withdrawal),
             "dispute" => Ok(OperationType::Dispute),
             "resolve" => Ok(OperationType::Resolve),
             "chargeback" => Ok(OperationType::Chargeback),
+            "reassign_hold" => Ok(OperationType::ReassignHold),
+            "bonus" | "promo" => Ok(OperationType::Bonus),
+            "split" => Ok(OperationType::Split),
+            "freeze" => Ok(OperationType::Freeze),
+            "unfreeze" => Ok(OperationType::Unfreeze),
             _ => Err(de::Error::unknown_variant(
                 &s,
-                &["deposit", "withdrawal", "dispute", "resolve", "chargeback"],
+                &[
+                    "deposit",
+                    "credit",
+                    "withdrawal",
+                    "debit",
+                    "dispute",
+                    "resolve",
+                    "chargeback",
+                    "reassign_hold",
+                    "bonus",
+                    "promo",
+                    "split",
+                    "freeze",
+                    "unfreeze",
+                ],
             )),
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
-pub struct OperationRecord {
+/// One input row. `C` and `A` are the client and amount as read: `u16` and
+/// `Decimal` normally, `String` when `--string-clients` or a custom amount syntax is
+/// enabled, until the engine resolves them.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OperationRecord<C = u16, A = Decimal> {
     pub r#type: OperationType,
-    pub client: u16,
+    pub client: C,
     pub tx: u32,
-    pub amount: Option<Decimal>,
+    pub amount: Option<A>,
+    /// Destination tx for `reassign_hold`; absent for every other operation.
+    #[serde(default)]
+    pub target: Option<u32>,
+    /// New deposits for `split`, written `tx:amount` separated by `;`
+    /// (e.g. `11:2.5;12:7.5`); absent for every other operation.
+    #[serde(default)]
+    pub parts: Option<SplitParts>,
 }
 
-#[derive(Debug, Clone)]
-pub struct TransactionState {
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitParts(pub Vec<(u32, Decimal)>);
+
+impl FromStr for SplitParts {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.split(';')
+            .map(|part| {
+                let (tx, amount) = part
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Invalid split part '{}' (expected tx:amount)", part))?;
+                let tx = tx
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid split part tx '{}'", tx))?;
+                let amount = Decimal::from_str(amount.trim())
+                    .with_context(|| format!("Invalid split part amount '{}'", amount))?;
+                Ok((tx, amount))
+            })
+            .collect::<Result<_>>()
+            .map(SplitParts)
+    }
+}
+
+impl<'de> Deserialize<'de> for SplitParts {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A logged deposit or withdrawal that can still be disputed.
+///
+/// Balance math for a dispute of amount `a`:
+///
+/// | | deposit | withdrawal |
+/// |---|---|---|
+/// | dispute | `available -= a`, `held += a` | `held += a` (the funds already left) |
+/// | resolve | `available += a`, `held -= a` | `held -= a` (claim discarded) |
+/// | chargeback | `held -= a`, lock | `held -= a`, `available += a`, lock |
+///
+/// So a deposit chargeback removes the funds and a withdrawal chargeback returns them.
+/// `a` is the amount held when the dispute opened (see `DisputeHoldPolicy`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransactionState<A = Decimal> {
     pub client: u16,
-    pub amount: Decimal,
+    pub amount: A,
     pub is_deposit: bool,
 }
 
-#[derive(Debug, Clone)]
-pub struct ClientBalance {
-    pub available: Decimal,
-    pub held: Decimal,
+/// Numeric type balances and the transaction log are kept in. `Engine` defaults to
+/// `Decimal`; another type (e.g. fixed-point integers) gets input amounts,
+/// configured limits and fees converted through `Decimal`.
+pub trait Amount: Copy + Ord + Default + fmt::Debug {
+    const ZERO: Self;
+
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+
+    fn checked_sub(self, rhs: Self) -> Option<Self>;
+
+    /// `None` if `value` is not representable.
+    fn from_decimal(value: Decimal) -> Option<Self>;
+
+    fn to_decimal(self) -> Decimal;
+}
+
+impl Amount for Decimal {
+    const ZERO: Self = Decimal::ZERO;
+
+    fn checked_add(self, rhs: Self) -> Option<Self> {
+        Decimal::checked_add(self, rhs)
+    }
+
+    fn checked_sub(self, rhs: Self) -> Option<Self> {
+        Decimal::checked_sub(self, rhs)
+    }
+
+    fn from_decimal(value: Decimal) -> Option<Self> {
+        Some(value)
+    }
+
+    fn to_decimal(self) -> Decimal {
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientBalance<A = Decimal> {
+    pub available: A,
+    pub held: A,
     pub locked: bool,
+    /// The chargeback tx that locked the account, if it is locked.
+    pub lock_reason: Option<u32>,
 }
 
 impl ClientBalance {
@@ -62,8 +209,146 @@ impl ClientBalance {
             available: Decimal::ZERO,
             held: Decimal::ZERO,
             locked: false,
+            lock_reason: None,
         }
     }
+
+    pub fn total(&self) -> Decimal {
+        self.available + self.held
+    }
+}
+
+/// Serializes as `available,held,total,locked`: a JSON output row under default
+/// `OutputOptions`, without the client.
+impl Serialize for ClientBalance {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        BalanceRow::unlabeled(self, &OutputOptions::default()).serialize(serializer)
+    }
+}
+
+/// Why `Engine::apply` left an operation unapplied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// A withdrawal over the withdrawable balance, or a hold rejected under
+    /// `DisputeHoldPolicy::RejectOverdispute`.
+    InsufficientFunds,
+    /// A withdrawal over `max_withdrawal`, whatever the balance.
+    ExceedsWithdrawalLimit,
+    AccountLocked,
+    /// A withdrawal while the client has a dispute holding funds.
+    OpenDispute,
+    /// A deposit or withdrawal reusing the tx id of a logged one, which belongs to
+    /// `owner`. The first occurrence wins: it stays in the log and later disputes of
+    /// the id refer to it.
+    DuplicateTx {
+        owner: u16,
+        client: u16,
+    },
+    /// The referenced tx is not in the log.
+    UnknownTx,
+    /// A withdrawal, dispute or other operation for a client with no earlier
+    /// deposit, bonus or freeze, so no balance to act on.
+    UnknownClient,
+    /// A dispute of a withdrawal under `deposit_disputes_only`.
+    DisputeOnNonDeposit,
+    /// A dispute on a tx whose earlier dispute was charged back.
+    AlreadySettled,
+    /// A dispute on a tx whose earlier dispute was resolved, under
+    /// `DisputePolicy::Once`.
+    DisputeAlreadyUsed,
+    /// The referenced tx belongs to `expected`, not to the row's client `actual`.
+    ClientMismatch {
+        expected: u16,
+        actual: u16,
+    },
+    /// A deposit, withdrawal or bonus whose amount is zero or negative.
+    NonPositiveAmount,
+    /// A row whose fields do not fit its type, e.g. a deposit with no amount or a
+    /// resolve with one. The message names the problem.
+    MalformedRecord(&'static str),
+    /// Under `ProcessingMode::Strict`, a row with `actual` fields against a header
+    /// of `expected`: more than the header, or too few to reach a column its type
+    /// needs.
+    MalformedRow {
+        expected: usize,
+        actual: usize,
+    },
+    AlreadyDisputed,
+    /// A resolve, chargeback or reassign of a tx with no open dispute.
+    NotDisputed,
+    /// A resolve or chargeback whose hold exceeds the client's `held`; applying it
+    /// would drive `held` negative, so the dispute stays open.
+    InsufficientHeld,
+    /// The dispute would push `held` over `max_disputed_amount_per_client`.
+    DisputeAmountCeiling,
+    /// The dispute's amount differs from the tx under `match_dispute_amount`, or
+    /// exceeds it under `partial_disputes`.
+    DisputeAmountMismatch,
+    /// A dispute with an `amount` of zero under `partial_disputes`, which could mean
+    /// either the whole tx or nothing.
+    ZeroDisputeAmount,
+    /// A malformed `reassign_hold` or `split`, or one aimed at a withdrawal.
+    InvalidOperation,
+    /// A deposit or withdrawal amount with more decimal places than `precision`,
+    /// under `ExcessPrecision::Reject`, or any amount the engine's `Amount` type
+    /// cannot represent.
+    ExcessivePrecision,
+    /// Applying the operation would overflow a balance (`available`, `held` or
+    /// their total).
+    ArithmeticOverflow,
+    /// Client 0 or tx 0 under `reject_zero_ids`.
+    ReservedId,
+    /// The client already had `max_tx_per_client` operations applied.
+    ClientRateLimited,
+}
+
+/// An operation that was read but not applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedRecord {
+    pub tx: u32,
+    pub client: u16,
+    pub r#type: OperationType,
+    pub reason: SkipReason,
+}
+
+/// A dispute that held less than the disputed amount, because the client had
+/// already spent part of it (`DisputeHoldPolicy::HoldAvailableOnly`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisputeShortfall {
+    pub client: u16,
+    pub tx: u32,
+    /// Disputed amount minus the amount actually held.
+    pub shortfall: Decimal,
+}
+
+/// A withdrawal that took less than it asked for, because the client did not have
+/// the rest (`partial_withdrawals`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct WithdrawalShortfall {
+    pub client: u16,
+    pub tx: u32,
+    /// Requested amount minus the amount actually withdrawn.
+    pub shortfall: Decimal,
+}
+
+/// Cumulative applied deposits and withdrawals of one client.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientFlows {
+    /// Gross deposit volume.
+    pub deposited: Decimal,
+    pub withdrawn: Decimal,
+    pub deposit_count: u64,
+    pub withdrawal_count: u64,
+    /// Withdrawal fees charged (see `WithdrawalFee`).
+    pub fees: Decimal,
+}
+
+impl ClientFlows {
+    /// Money in minus money out. Unlike `available`, unaffected by holds and
+    /// chargebacks.
+    pub fn net_flow(&self) -> Decimal {
+        self.deposited - self.withdrawn
+    }
 }
 
 #[cfg(test)]
@@ -71,6 +356,7 @@ mod tests {
     use super::*;
     use csv::ReaderBuilder;
     use rust_decimal_macros::dec;
+    use std::collections::HashMap;
     use std::io::Cursor;
 
     #[test]
@@ -94,6 +380,87 @@ mod tests {
         assert_eq!(rec2.r#type, OperationType::Withdrawal);
     }
 
+    #[test]
+    fn test_reassign_hold_target() {
+        let data = "type,client,tx,amount,target\nreassign_hold,1,4,,9\ndeposit,1,5,1.0";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let rec: OperationRecord = rdr.deserialize().next().unwrap().unwrap();
+        assert_eq!(rec.r#type, OperationType::ReassignHold);
+        assert_eq!(rec.amount, None);
+        assert_eq!(rec.target, Some(9));
+        let rec: OperationRecord = rdr.deserialize().next().unwrap().unwrap();
+        assert_eq!(rec.target, None);
+    }
+
+    #[test]
+    fn test_split_parts() {
+        let data = "type,client,tx,amount,target,parts\nsplit,1,4,,,11:2.5; 12:7.5\n";
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let rec: OperationRecord = rdr.deserialize().next().unwrap().unwrap();
+        assert_eq!(rec.r#type, OperationType::Split);
+        assert_eq!(
+            rec.parts,
+            Some(SplitParts(vec![(11, dec!(2.5)), (12, dec!(7.5))]))
+        );
+        assert!("11-2.5".parse::<SplitParts>().is_err());
+        assert!("x:2.5".parse::<SplitParts>().is_err());
+    }
+
+    #[test]
+    fn test_balance_serialization_round_trip() -> Result<()> {
+        let balance = ClientBalance {
+            available: dec!(1.23456),
+            held: dec!(2),
+            locked: true,
+            lock_reason: None,
+        };
+        let json = serde_json::to_value(&balance)?;
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "available": "1.2346",
+                "held": "2.0000",
+                "total": "3.2346",
+                "locked": true,
+            })
+        );
+        let amount = |field: &str| Decimal::from_str(json[field].as_str().unwrap());
+        assert_eq!(amount("available")?, balance.available.round_dp(4));
+        assert_eq!(amount("held")?, balance.held);
+        assert_eq!(amount("total")?, balance.total().round_dp(4));
+
+        // The same fields as a JSON output row, which adds the client.
+        let balances = HashMap::from([(7, balance)]);
+        let mut ndjson = Vec::new();
+        crate::output::write_ndjson(&balances, &Default::default(), &mut ndjson)?;
+        let mut row: serde_json::Value = serde_json::from_slice(&ndjson)?;
+        assert_eq!(row["client"], 7);
+        row.as_object_mut().unwrap().remove("client");
+        assert_eq!(row, json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_credit_debit_aliases() {
+        let data = "type,client,tx,amount\nCREDIT,1,1,2.0\nDebit,1,2,1.0\nrefund,1,3,1.0";
+        let mut rdr = ReaderBuilder::new().from_reader(Cursor::new(data));
+        let mut records = rdr.deserialize::<OperationRecord>();
+        assert_eq!(
+            records.next().unwrap().unwrap().r#type,
+            OperationType::Deposit
+        );
+        assert_eq!(
+            records.next().unwrap().unwrap().r#type,
+            OperationType::Withdrawal
+        );
+        let err = records.next().unwrap().unwrap_err().to_string();
+        assert!(err.contains("`deposit`, `credit`, `withdrawal`, `debit`"));
+    }
+
     #[test]
     fn test_missing_amount() {
         let data = "type,client,tx\ndispute,1,1";
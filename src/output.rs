@@ -0,0 +1,1219 @@
+use anyhow::{anyhow, Context, Result};
+use csv::{Writer, WriterBuilder};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::client_id::ClientInterner;
+use crate::hash_chain::HashChain;
+use crate::models::{ClientBalance, ClientFlows};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Ndjson,
+    Json,
+    Parquet,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(OutputFormat::Csv),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "json" => Ok(OutputFormat::Json),
+            "parquet" => Ok(OutputFormat::Parquet),
+            _ => Err(anyhow!(
+                "Unknown output format '{}' (expected csv, ndjson, json or parquet)",
+                s
+            )),
+        }
+    }
+}
+
+/// Order of client rows in the output.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SortKey {
+    #[default]
+    Client,
+    /// Ascending `available + held`, ties broken by client id.
+    Total,
+    /// Ascending `available`, ties broken by client id.
+    Available,
+}
+
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "client" => Ok(SortKey::Client),
+            "total" => Ok(SortKey::Total),
+            "available" => Ok(SortKey::Available),
+            _ => Err(anyhow!(
+                "Unknown sort key '{}' (expected client, total or available)",
+                s
+            )),
+        }
+    }
+}
+
+/// A CSV output column, selectable with `--columns`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    NetFlow,
+    ClientHash,
+    Deposits,
+    Withdrawals,
+    Volume,
+    Fees,
+    LockedBy,
+}
+
+impl Column {
+    const ALL: [Column; 12] = [
+        Column::Client,
+        Column::Available,
+        Column::Held,
+        Column::Total,
+        Column::Locked,
+        Column::NetFlow,
+        Column::ClientHash,
+        Column::Deposits,
+        Column::Withdrawals,
+        Column::Volume,
+        Column::Fees,
+        Column::LockedBy,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Client => "client",
+            Column::Available => "available",
+            Column::Held => "held",
+            Column::Total => "total",
+            Column::Locked => "locked",
+            Column::NetFlow => "net_flow",
+            Column::ClientHash => "client_hash",
+            Column::Deposits => "deposits",
+            Column::Withdrawals => "withdrawals",
+            Column::Volume => "volume",
+            Column::Fees => "fees",
+            Column::LockedBy => "locked_by",
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        Column::ALL
+            .into_iter()
+            .find(|column| column.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let known: Vec<&str> = Column::ALL.iter().map(|c| c.name()).collect();
+                anyhow!("Unknown column '{}' (expected {})", s, known.join(", "))
+            })
+    }
+}
+
+/// Parses a comma-separated `--columns` list.
+pub fn parse_columns(list: &str) -> Result<Vec<Column>> {
+    list.split(',').map(str::parse).collect()
+}
+
+/// Presentation settings shared by every output format.
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// Under `--string-clients`, renders interned ids back as their original names.
+    pub client_names: Option<ClientInterner>,
+    /// Under `--anonymize`, the replacement id written out for each real client id.
+    pub anonymized_ids: Option<HashMap<u16, u16>>,
+    /// Truncate amounts toward zero instead of rounding, so funds are never overstated.
+    pub truncate: bool,
+    /// Zero-pad numeric client ids in CSV output to this many digits.
+    pub pad_client: Option<usize>,
+    /// Per-client flows, tracked under `--with-stats` or `--verbose`.
+    pub client_flows: Option<HashMap<u16, ClientFlows>>,
+    /// Add a `net_flow` column from `client_flows`.
+    pub with_stats: bool,
+    /// Add `deposits`, `withdrawals` and `volume` columns from `client_flows`, and a
+    /// `locked_by` column with the chargeback tx that locked the account.
+    pub verbose: bool,
+    /// Add a `fees` column (withdrawal fees charged) from `client_flows`.
+    pub report_fees: bool,
+    /// Under `--client-hash`, per-client hash chains rendered as a `client_hash` column.
+    pub client_hashes: Option<HashMap<u16, HashChain>>,
+    pub sort_by: SortKey,
+    /// Reverse the `sort_by` order; ties still break by ascending client id.
+    pub descending: bool,
+    /// List unlocked clients before locked ones, each group in `sort_by` order.
+    pub group_by_locked: bool,
+    /// Write only the first this many clients, in output order.
+    pub max_rows: Option<usize>,
+    /// CSV columns in the order to write them; `None` writes the standard layout.
+    pub columns: Option<Vec<Column>>,
+    /// Decimal places amounts are written with; `None` means `DEFAULT_PRECISION`.
+    pub precision: Option<u32>,
+    /// CSV field separator; `None` means a comma.
+    pub delimiter: Option<u8>,
+}
+
+pub const DEFAULT_PRECISION: u32 = 4;
+
+/// rust_decimal's maximum scale.
+pub const MAX_PRECISION: u32 = 28;
+
+impl OutputOptions {
+    fn precision(&self) -> u32 {
+        self.precision.unwrap_or(DEFAULT_PRECISION)
+    }
+
+    fn amount(&self, value: Decimal) -> Decimal {
+        if self.truncate {
+            value.trunc_with_scale(self.precision())
+        } else {
+            value.round_dp(self.precision())
+        }
+    }
+
+    fn format_amount(&self, value: Decimal) -> String {
+        if self.truncate {
+            format!("{:.*}", self.precision() as usize, self.amount(value))
+        } else {
+            format_decimal(value, self.precision())
+        }
+    }
+
+    fn presented_id(&self, id: u16) -> u16 {
+        self.anonymized_ids
+            .as_ref()
+            .and_then(|ids| ids.get(&id).copied())
+            .unwrap_or(id)
+    }
+
+    /// Per-client formatting, drawing on this value's own client data.
+    fn rows(&self) -> RowFormat<'_> {
+        RowFormat {
+            options: self,
+            clients: ClientData {
+                names: self.client_names.as_ref(),
+                flows: self.client_flows.as_ref(),
+                hashes: self.client_hashes.as_ref(),
+            },
+        }
+    }
+
+    /// Original label of a client, ignoring anonymization (used for the mapping file).
+    fn original_label(&self, id: u16) -> String {
+        match self.client_names.as_ref().and_then(|names| names.name(id)) {
+            Some(name) => name.to_string(),
+            None => id.to_string(),
+        }
+    }
+}
+
+/// Per-client data the output draws on, borrowed from wherever it is kept: an
+/// `OutputOptions` writing the final balances, or a live `Engine` under `--serve`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientData<'a> {
+    pub names: Option<&'a ClientInterner>,
+    pub flows: Option<&'a HashMap<u16, ClientFlows>>,
+    pub hashes: Option<&'a HashMap<u16, HashChain>>,
+}
+
+/// `OutputOptions` paired with the client data its rows are formatted from.
+#[derive(Clone, Copy)]
+struct RowFormat<'a> {
+    options: &'a OutputOptions,
+    clients: ClientData<'a>,
+}
+
+impl<'a> RowFormat<'a> {
+    /// Output order of two clients, before any `sort_by`: by name under
+    /// `--string-clients`, otherwise by id as presented.
+    fn cmp_clients(&self, a: u16, b: u16) -> Ordering {
+        match (self.client_label(a), self.client_label(b)) {
+            (ClientLabel::Name(x), ClientLabel::Name(y)) => x.cmp(y),
+            _ => self
+                .options
+                .presented_id(a)
+                .cmp(&self.options.presented_id(b)),
+        }
+    }
+
+    fn client_label(&self, id: u16) -> ClientLabel<'a> {
+        if self.options.anonymized_ids.is_some() {
+            return ClientLabel::Id(self.options.presented_id(id));
+        }
+        match self.clients.names.and_then(|names| names.name(id)) {
+            Some(name) => ClientLabel::Name(name),
+            None => ClientLabel::Id(id),
+        }
+    }
+
+    /// Client column as written to CSV, zero-padded under `--pad-client`. Names are
+    /// left as they are.
+    fn csv_client(&self, id: u16) -> String {
+        match (self.client_label(id), self.options.pad_client) {
+            (ClientLabel::Id(id), Some(width)) => format!("{:0width$}", id),
+            (label, _) => label.to_string(),
+        }
+    }
+
+    fn flows(&self, id: u16) -> Option<ClientFlows> {
+        let flows = self.clients.flows?;
+        Some(flows.get(&id).cloned().unwrap_or_default())
+    }
+
+    fn net_flow(&self, id: u16) -> Option<String> {
+        let flows = self.flows(id).filter(|_| self.options.with_stats)?;
+        Some(self.options.format_amount(flows.net_flow()))
+    }
+
+    /// The client's flows, for the `--verbose` activity columns.
+    fn activity(&self, id: u16) -> Option<ClientFlows> {
+        self.flows(id).filter(|_| self.options.verbose)
+    }
+
+    fn fees(&self, id: u16) -> Option<String> {
+        let flows = self.flows(id).filter(|_| self.options.report_fees)?;
+        Some(self.options.format_amount(flows.fees))
+    }
+
+    fn client_hash(&self, id: u16) -> Option<String> {
+        let chains = self.clients.hashes?;
+        Some(chains.get(&id).cloned().unwrap_or_default().to_hex())
+    }
+
+    fn csv_columns(&self) -> Vec<Column> {
+        if let Some(columns) = &self.options.columns {
+            return columns.clone();
+        }
+        let mut columns = vec![
+            Column::Client,
+            Column::Available,
+            Column::Held,
+            Column::Total,
+            Column::Locked,
+        ];
+        let tracked = self.clients.flows.is_some();
+        if self.options.with_stats && tracked {
+            columns.push(Column::NetFlow);
+        }
+        if self.options.verbose && tracked {
+            columns.extend([
+                Column::Deposits,
+                Column::Withdrawals,
+                Column::Volume,
+                Column::LockedBy,
+            ]);
+        }
+        if self.options.report_fees && tracked {
+            columns.push(Column::Fees);
+        }
+        if self.clients.hashes.is_some() {
+            columns.push(Column::ClientHash);
+        }
+        columns
+    }
+
+    fn csv_field(&self, column: Column, id: u16, balance: &ClientBalance) -> String {
+        match column {
+            Column::Client => self.csv_client(id),
+            Column::Available => self.options.format_amount(balance.available),
+            Column::Held => self.options.format_amount(balance.held),
+            Column::Total => self.options.format_amount(balance.total()),
+            Column::Locked => balance.locked.to_string(),
+            Column::NetFlow => self.net_flow(id).unwrap_or_default(),
+            Column::ClientHash => self.client_hash(id).unwrap_or_default(),
+            Column::Deposits => self
+                .activity(id)
+                .map(|flows| flows.deposit_count.to_string())
+                .unwrap_or_default(),
+            Column::Withdrawals => self
+                .activity(id)
+                .map(|flows| flows.withdrawal_count.to_string())
+                .unwrap_or_default(),
+            Column::Volume => self
+                .activity(id)
+                .map(|flows| self.options.format_amount(flows.deposited))
+                .unwrap_or_default(),
+            Column::Fees => self.fees(id).unwrap_or_default(),
+            Column::LockedBy => balance
+                .lock_reason
+                .map(|tx| tx.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Where `--summary` writes its aggregate totals.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SummaryTarget {
+    /// `#` comment lines after the CSV rows.
+    Footer,
+    Stderr,
+}
+
+impl FromStr for SummaryTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "footer" => Ok(SummaryTarget::Footer),
+            "stderr" => Ok(SummaryTarget::Stderr),
+            _ => Err(anyhow!(
+                "Unknown summary target '{}' (expected footer or stderr)",
+                s
+            )),
+        }
+    }
+}
+
+/// Totals over every client's final balance, reported by `--summary`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BalanceSummary {
+    pub clients: usize,
+    pub locked: usize,
+    pub available: Decimal,
+    pub held: Decimal,
+}
+
+impl BalanceSummary {
+    pub fn of(client_balances: &HashMap<u16, ClientBalance>) -> Self {
+        let mut summary = Self {
+            clients: client_balances.len(),
+            ..Self::default()
+        };
+        for balance in client_balances.values() {
+            summary.locked += usize::from(balance.locked);
+            // Saturating: a report line is no reason to fail a run whose balances
+            // were all in range.
+            summary.available = summary.available.saturating_add(balance.available);
+            summary.held = summary.held.saturating_add(balance.held);
+        }
+        summary
+    }
+
+    /// The summary as `# `-prefixed lines, amounts formatted like the balance rows.
+    pub fn lines(&self, options: &OutputOptions) -> Vec<String> {
+        vec![
+            format!("# clients: {}", self.clients),
+            format!("# locked: {}", self.locked),
+            format!("# available: {}", options.format_amount(self.available)),
+            format!("# held: {}", options.format_amount(self.held)),
+        ]
+    }
+}
+
+/// Message for stderr when `max_rows` left clients out of the output.
+pub fn truncation_note(client_count: usize, options: &OutputOptions) -> Option<String> {
+    let max_rows = options.max_rows.filter(|&max| client_count > max)?;
+    Some(format!(
+        "output truncated to {} of {} clients (--max-output-rows)",
+        max_rows, client_count
+    ))
+}
+
+/// Assigns sequential ids `1..=N` to clients in first-appearance order.
+pub fn anonymize(first_seen: &[u16]) -> Result<HashMap<u16, u16>> {
+    first_seen
+        .iter()
+        .enumerate()
+        .map(|(i, &client)| {
+            let id = u16::try_from(i + 1)
+                .map_err(|_| anyhow!("Cannot anonymize more than {} clients", u16::MAX))?;
+            Ok((client, id))
+        })
+        .collect()
+}
+
+/// Writes `anonymized,client` pairs so anonymized output can be traced back.
+pub fn write_anonymization_map(
+    first_seen: &[u16],
+    options: &OutputOptions,
+    out: impl Write,
+) -> Result<()> {
+    let mut wtr = Writer::from_writer(out);
+    wtr.write_record(["anonymized", "client"])
+        .context("Failed to write header")?;
+    for &client in first_seen {
+        wtr.write_record(&[
+            options.presented_id(client).to_string(),
+            options.original_label(client),
+        ])
+        .context("Failed to write record")?;
+    }
+    wtr.flush().context("Failed to flush output")?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum ClientLabel<'a> {
+    Id(u16),
+    Name(&'a str),
+}
+
+impl std::fmt::Display for ClientLabel<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientLabel::Id(id) => write!(f, "{}", id),
+            ClientLabel::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Client ids in output order, per `options.sort_by`. The client as presented (its
+/// name under `--string-clients`) is always the final tiebreaker, so the order
+/// never depends on map iteration order.
+pub fn sorted_client_ids(
+    client_balances: &HashMap<u16, ClientBalance>,
+    options: &OutputOptions,
+) -> Vec<u16> {
+    let rows = options.rows();
+    let mut client_ids: Vec<u16> = client_balances.keys().cloned().collect();
+    client_ids.sort_by(|a, b| {
+        let (x, y) = (&client_balances[a], &client_balances[b]);
+        let order = match options.sort_by {
+            SortKey::Client => Ordering::Equal,
+            SortKey::Total => x.total().cmp(&y.total()),
+            SortKey::Available => x.available.cmp(&y.available),
+        };
+        let by_id = rows.cmp_clients(*a, *b);
+        match (options.descending, options.sort_by) {
+            (true, SortKey::Client) => by_id.reverse(),
+            (true, _) => order.reverse().then(by_id),
+            (false, _) => order.then(by_id),
+        }
+    });
+    if options.group_by_locked {
+        // Stable, so each group keeps the order above.
+        client_ids.sort_by_key(|id| client_balances[id].locked);
+    }
+    if let Some(max_rows) = options.max_rows {
+        client_ids.truncate(max_rows);
+    }
+    client_ids
+}
+
+/// A text output format for the final balances. Parquet needs a seekable file
+/// rather than any `Write`, so it stays outside this trait (see `write_parquet`).
+pub trait OutputWriter {
+    fn write(
+        &self,
+        client_balances: &HashMap<u16, ClientBalance>,
+        options: &OutputOptions,
+        out: &mut dyn Write,
+    ) -> Result<()>;
+}
+
+pub struct CsvWriter;
+
+impl OutputWriter for CsvWriter {
+    fn write(
+        &self,
+        client_balances: &HashMap<u16, ClientBalance>,
+        options: &OutputOptions,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        write_csv(client_balances, options, out)
+    }
+}
+
+pub struct NdjsonWriter;
+
+impl OutputWriter for NdjsonWriter {
+    fn write(
+        &self,
+        client_balances: &HashMap<u16, ClientBalance>,
+        options: &OutputOptions,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        write_ndjson(client_balances, options, out)
+    }
+}
+
+pub struct JsonWriter;
+
+impl OutputWriter for JsonWriter {
+    fn write(
+        &self,
+        client_balances: &HashMap<u16, ClientBalance>,
+        options: &OutputOptions,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        write_json(client_balances, options, out)
+    }
+}
+
+pub fn write_csv(
+    client_balances: &HashMap<u16, ClientBalance>,
+    options: &OutputOptions,
+    out: impl Write,
+) -> Result<()> {
+    let mut wtr = WriterBuilder::new()
+        .delimiter(options.delimiter.unwrap_or(b','))
+        .from_writer(out);
+    let rows = options.rows();
+    let columns = rows.csv_columns();
+    wtr.write_record(columns.iter().map(|column| column.name()))
+        .context("Failed to write header")?;
+
+    for id in sorted_client_ids(client_balances, options) {
+        let balance = client_balances.get(&id).unwrap();
+        wtr.write_record(
+            columns
+                .iter()
+                .map(|&column| rows.csv_field(column, id, balance)),
+        )
+        .context("Failed to write record")?;
+    }
+
+    wtr.flush().context("Failed to flush output")?;
+    Ok(())
+}
+
+/// One client's row as `write_csv` writes it, without the header, taking the client
+/// data from `clients` rather than from `options`.
+pub fn format_csv_row(
+    id: u16,
+    balance: &ClientBalance,
+    options: &OutputOptions,
+    clients: ClientData<'_>,
+) -> Result<String> {
+    let rows = RowFormat { options, clients };
+    let mut wtr = WriterBuilder::new()
+        .delimiter(options.delimiter.unwrap_or(b','))
+        .from_writer(Vec::new());
+    wtr.write_record(
+        rows.csv_columns()
+            .iter()
+            .map(|&column| rows.csv_field(column, id, balance)),
+    )
+    .context("Failed to write record")?;
+    let bytes = wtr.into_inner().context("Failed to flush output")?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// One client's balances as rendered by the JSON-based formats.
+///
+/// Amounts are strings with `--precision` places rather than JSON numbers, so
+/// consumers never round them through floating point.
+#[derive(Debug, Serialize)]
+pub(crate) struct BalanceRow<'a> {
+    /// Absent only in `ClientBalance`'s own `Serialize`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<ClientLabel<'a>>,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    net_flow: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deposits: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    withdrawals: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fees: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locked_by: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_hash: Option<String>,
+}
+
+impl<'a> BalanceRow<'a> {
+    fn new(id: u16, balance: &ClientBalance, options: &'a OutputOptions) -> Self {
+        let rows = options.rows();
+        let activity = rows.activity(id);
+        Self {
+            client: Some(rows.client_label(id)),
+            available: options.format_amount(balance.available),
+            held: options.format_amount(balance.held),
+            total: options.format_amount(balance.total()),
+            locked: balance.locked,
+            net_flow: rows.net_flow(id),
+            deposits: activity.as_ref().map(|flows| flows.deposit_count),
+            withdrawals: activity.as_ref().map(|flows| flows.withdrawal_count),
+            volume: activity.map(|flows| options.format_amount(flows.deposited)),
+            fees: rows.fees(id),
+            locked_by: balance.lock_reason.filter(|_| options.verbose),
+            client_hash: rows.client_hash(id),
+        }
+    }
+
+    /// Just the balance columns, with no client or per-client extras.
+    pub(crate) fn unlabeled(balance: &ClientBalance, options: &'a OutputOptions) -> Self {
+        Self {
+            client: None,
+            available: options.format_amount(balance.available),
+            held: options.format_amount(balance.held),
+            total: options.format_amount(balance.total()),
+            locked: balance.locked,
+            net_flow: None,
+            deposits: None,
+            withdrawals: None,
+            volume: None,
+            fees: None,
+            locked_by: None,
+            client_hash: None,
+        }
+    }
+}
+
+/// Writes one JSON object per client per line, in `sorted_client_ids` order.
+pub fn write_ndjson(
+    client_balances: &HashMap<u16, ClientBalance>,
+    options: &OutputOptions,
+    mut out: impl Write,
+) -> Result<()> {
+    for id in sorted_client_ids(client_balances, options) {
+        let row = BalanceRow::new(id, &client_balances[&id], options);
+        serde_json::to_writer(&mut out, &row).context("Failed to write record")?;
+        out.write_all(b"\n").context("Failed to write record")?;
+    }
+    out.flush().context("Failed to flush output")?;
+    Ok(())
+}
+
+/// Writes all clients as a single JSON array, in `sorted_client_ids` order.
+pub fn write_json(
+    client_balances: &HashMap<u16, ClientBalance>,
+    options: &OutputOptions,
+    mut out: impl Write,
+) -> Result<()> {
+    let rows: Vec<BalanceRow> = sorted_client_ids(client_balances, options)
+        .into_iter()
+        .map(|id| BalanceRow::new(id, &client_balances[&id], options))
+        .collect();
+    serde_json::to_writer_pretty(&mut out, &rows).context("Failed to write JSON output")?;
+    out.write_all(b"\n")
+        .context("Failed to write JSON output")?;
+    out.flush().context("Failed to flush output")?;
+    Ok(())
+}
+
+/// `value` rounded to `precision` places (banker's rounding) and padded with
+/// trailing zeros to exactly that many.
+pub fn format_decimal(value: Decimal, precision: u32) -> String {
+    format!("{:.*}", precision as usize, value.round_dp(precision))
+}
+
+/// Writes balances as a single Parquet row group.
+///
+/// Amounts are stored as `Decimal128(38, precision)` so readers see the same values
+/// as the CSV output without going through floating point.
+#[cfg(feature = "parquet")]
+pub fn write_parquet(
+    client_balances: &HashMap<u16, ClientBalance>,
+    options: &OutputOptions,
+    out: std::fs::File,
+) -> Result<()> {
+    use arrow_array::{ArrayRef, BooleanArray, Decimal128Array, RecordBatch, UInt16Array};
+    use arrow_schema::{DataType, Field, Schema};
+    use parquet::arrow::ArrowWriter;
+    use std::sync::Arc;
+
+    let scale = options.precision();
+    let decimal_column = |values: Vec<Decimal>| -> Result<ArrayRef> {
+        let array = Decimal128Array::from_iter_values(values.into_iter().map(|v| {
+            let mut scaled = options.amount(v);
+            scaled.rescale(scale);
+            scaled.mantissa()
+        }))
+        .with_precision_and_scale(38, scale as i8)?;
+        Ok(Arc::new(array))
+    };
+
+    let client_ids = sorted_client_ids(client_balances, options);
+    let balances: Vec<&ClientBalance> = client_ids.iter().map(|id| &client_balances[id]).collect();
+    let presented_ids: Vec<u16> = client_ids
+        .iter()
+        .map(|&id| options.presented_id(id))
+        .collect();
+
+    let decimal = DataType::Decimal128(38, scale as i8);
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("available", decimal.clone(), false),
+        Field::new("held", decimal.clone(), false),
+        Field::new("total", decimal, false),
+        Field::new("locked", DataType::Boolean, false),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema.clone(),
+        vec![
+            Arc::new(UInt16Array::from(presented_ids)),
+            decimal_column(balances.iter().map(|b| b.available).collect())?,
+            decimal_column(balances.iter().map(|b| b.held).collect())?,
+            decimal_column(balances.iter().map(|b| b.total()).collect())?,
+            Arc::new(BooleanArray::from(
+                balances.iter().map(|b| b.locked).collect::<Vec<_>>(),
+            )),
+        ],
+    )
+    .context("Failed to build Parquet record batch")?;
+
+    let mut writer =
+        ArrowWriter::try_new(out, schema, None).context("Failed to create Parquet writer")?;
+    writer
+        .write(&batch)
+        .context("Failed to write Parquet batch")?;
+    writer.close().context("Failed to finish Parquet file")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_ndjson_one_object_per_line() -> Result<()> {
+        let mut balances = HashMap::new();
+        balances.insert(
+            7,
+            ClientBalance {
+                available: dec!(-2.5),
+                held: dec!(5),
+                locked: true,
+                lock_reason: None,
+            },
+        );
+        balances.insert(
+            3,
+            ClientBalance {
+                available: dec!(1.23456),
+                held: dec!(0),
+                locked: false,
+                lock_reason: None,
+            },
+        );
+
+        let mut buf = Vec::new();
+        write_ndjson(&balances, &OutputOptions::default(), &mut buf)?;
+        let text = String::from_utf8(buf)?;
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["client"], 3);
+        assert_eq!(lines[0]["available"], "1.2346");
+        assert_eq!(lines[0]["total"], "1.2346");
+        assert_eq!(lines[0]["locked"], false);
+        assert_eq!(lines[1]["client"], 7);
+        assert_eq!(lines[1]["available"], "-2.5000");
+        assert_eq!(lines[1]["held"], "5.0000");
+        assert_eq!(lines[1]["total"], "2.5000");
+        assert_eq!(lines[1]["locked"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_writer_round_trip() -> Result<()> {
+        let mut balances = HashMap::new();
+        balances.insert(
+            2,
+            ClientBalance {
+                available: dec!(1.5),
+                held: dec!(0.25),
+                locked: false,
+                lock_reason: None,
+            },
+        );
+        balances.insert(
+            1,
+            ClientBalance {
+                available: dec!(-3),
+                held: dec!(10),
+                locked: true,
+                lock_reason: None,
+            },
+        );
+
+        let mut buf = Vec::new();
+        JsonWriter.write(&balances, &OutputOptions::default(), &mut buf)?;
+        let rows: Vec<serde_json::Value> = serde_json::from_slice(&buf)?;
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["client"], 1);
+        assert_eq!(rows[0]["locked"], true);
+        assert_eq!(rows[1]["client"], 2);
+        for row in &rows {
+            let amount = |field: &str| Decimal::from_str(row[field].as_str().unwrap()).unwrap();
+            assert_eq!(amount("total"), amount("available") + amount("held"));
+        }
+        assert_eq!(rows[0]["total"], "7.0000");
+        assert_eq!(rows[1]["total"], "1.7500");
+        Ok(())
+    }
+
+    #[test]
+    fn test_verbose_activity_columns() -> Result<()> {
+        let locked = ClientBalance {
+            locked: true,
+            lock_reason: Some(7),
+            ..ClientBalance::new()
+        };
+        let balances = HashMap::from([(1, ClientBalance::new()), (2, locked)]);
+        let flows = ClientFlows {
+            deposited: dec!(12.5),
+            withdrawn: dec!(3),
+            deposit_count: 2,
+            withdrawal_count: 1,
+            ..ClientFlows::default()
+        };
+        let options = OutputOptions {
+            client_flows: Some(HashMap::from([(1, flows)])),
+            verbose: true,
+            ..OutputOptions::default()
+        };
+
+        let mut buf = Vec::new();
+        write_csv(&balances, &options, &mut buf)?;
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "client,available,held,total,locked,deposits,withdrawals,volume,locked_by\n\
+             1,0.0000,0.0000,0.0000,false,2,1,12.5000,\n\
+             2,0.0000,0.0000,0.0000,true,0,0,0.0000,7\n"
+        );
+
+        let mut buf = Vec::new();
+        write_ndjson(&balances, &options, &mut buf)?;
+        let first: serde_json::Value =
+            serde_json::from_str(String::from_utf8(buf)?.lines().next().unwrap())?;
+        assert_eq!(first["deposits"], 2);
+        assert_eq!(first["withdrawals"], 1);
+        assert_eq!(first["volume"], "12.5000");
+        assert!(first.get("net_flow").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_precision() {
+        let two = OutputOptions {
+            precision: Some(2),
+            ..OutputOptions::default()
+        };
+        assert_eq!(two.format_amount(dec!(1.5)), "1.50");
+        assert_eq!(two.format_amount(dec!(1.005)), "1.00");
+        assert_eq!(two.format_amount(dec!(1.015)), "1.02");
+        assert_eq!(two.format_amount(dec!(-2.678)), "-2.68");
+
+        let eight = OutputOptions {
+            precision: Some(8),
+            ..OutputOptions::default()
+        };
+        assert_eq!(eight.format_amount(dec!(1.5)), "1.50000000");
+        assert_eq!(eight.format_amount(dec!(0.123456785)), "0.12345678");
+        assert_eq!(eight.format_amount(dec!(0.123456795)), "0.12345680");
+    }
+
+    #[test]
+    fn test_large_and_tiny_amounts_never_use_exponents() -> Result<()> {
+        assert_eq!(
+            format_decimal(dec!(10000000000.9999), 4),
+            "10000000000.9999"
+        );
+        assert_eq!(
+            format_decimal(Decimal::MAX, 0),
+            "79228162514264337593543950335"
+        );
+        assert_eq!(
+            format_decimal(dec!(0.0000000000000000000000000001), MAX_PRECISION),
+            "0.0000000000000000000000000001"
+        );
+
+        let mut balances = HashMap::new();
+        balances.insert(
+            1,
+            ClientBalance {
+                available: dec!(10000000000.9999),
+                held: dec!(0.00001),
+                locked: false,
+                lock_reason: None,
+            },
+        );
+        let truncated = OutputOptions {
+            truncate: true,
+            ..OutputOptions::default()
+        };
+        for options in [OutputOptions::default(), truncated] {
+            let mut csv = Vec::new();
+            CsvWriter.write(&balances, &options, &mut csv)?;
+            let mut json = Vec::new();
+            JsonWriter.write(&balances, &options, &mut json)?;
+            for out in [csv, json] {
+                let text = String::from_utf8(out)?;
+                assert!(text.contains("10000000000.9999"), "{}", text);
+                let numbers = text
+                    .split(|c: char| !c.is_ascii_alphanumeric() && c != '.')
+                    .filter(|token| token.starts_with(|c: char| c.is_ascii_digit()));
+                for number in numbers {
+                    assert!(Decimal::from_str_exact(number).is_ok(), "{}", number);
+                    assert!(!number.contains(['e', 'E']), "{}", number);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_vs_round() {
+        let rounded = OutputOptions::default();
+        let truncated = OutputOptions {
+            truncate: true,
+            ..OutputOptions::default()
+        };
+        assert_eq!(rounded.format_amount(dec!(1.23499)), "1.2350");
+        assert_eq!(truncated.format_amount(dec!(1.23499)), "1.2349");
+        assert_eq!(truncated.format_amount(dec!(-1.23499)), "-1.2349");
+        assert_eq!(truncated.format_amount(dec!(2)), "2.0000");
+    }
+
+    #[test]
+    fn test_sort_by_total_ties_break_by_client_id() {
+        let balance = |available, held| ClientBalance {
+            available,
+            held,
+            ..ClientBalance::new()
+        };
+        let mut balances = HashMap::new();
+        balances.insert(9, balance(dec!(1.0), dec!(0)));
+        balances.insert(7, balance(dec!(5.0), dec!(0)));
+        balances.insert(12, balance(dec!(4.0), dec!(1.0)));
+        balances.insert(2, balance(dec!(3.0), dec!(2.0)));
+        balances.insert(4, balance(dec!(0.75), dec!(0.25)));
+
+        let options = OutputOptions {
+            sort_by: SortKey::Total,
+            ..OutputOptions::default()
+        };
+        assert_eq!(sorted_client_ids(&balances, &options), vec![4, 9, 2, 7, 12]);
+        assert_eq!(
+            sorted_client_ids(&balances, &OutputOptions::default()),
+            vec![2, 4, 7, 9, 12]
+        );
+    }
+
+    #[test]
+    fn test_sort_descending() {
+        let balance = |available, held| ClientBalance {
+            available,
+            held,
+            ..ClientBalance::new()
+        };
+        let mut balances = HashMap::new();
+        balances.insert(3, balance(dec!(1.0), dec!(4.0)));
+        balances.insert(1, balance(dec!(2.0), dec!(0)));
+        balances.insert(8, balance(dec!(5.0), dec!(0)));
+        balances.insert(5, balance(dec!(3.0), dec!(0)));
+
+        let sorted = |sort_by, descending| {
+            let options = OutputOptions {
+                sort_by,
+                descending,
+                ..OutputOptions::default()
+            };
+            sorted_client_ids(&balances, &options)
+        };
+        assert_eq!(sorted(SortKey::Total, true), vec![3, 8, 5, 1]);
+        assert_eq!(sorted(SortKey::Available, true), vec![8, 5, 1, 3]);
+        assert_eq!(sorted(SortKey::Available, false), vec![3, 1, 5, 8]);
+        assert_eq!(sorted(SortKey::Client, true), vec![8, 5, 3, 1]);
+    }
+
+    #[test]
+    fn test_group_by_locked() {
+        let mut balances = HashMap::new();
+        for (id, locked) in [(5, true), (1, false), (3, true), (4, false), (2, true)] {
+            balances.insert(
+                id,
+                ClientBalance {
+                    locked,
+                    lock_reason: None,
+                    ..ClientBalance::new()
+                },
+            );
+        }
+        let options = OutputOptions {
+            group_by_locked: true,
+            ..OutputOptions::default()
+        };
+        assert_eq!(sorted_client_ids(&balances, &options), vec![1, 4, 2, 3, 5]);
+    }
+
+    #[test]
+    fn test_max_rows() -> Result<()> {
+        let balances: HashMap<u16, ClientBalance> =
+            (1..=5).map(|id| (id, ClientBalance::new())).collect();
+        let options = OutputOptions {
+            max_rows: Some(2),
+            sort_by: SortKey::Total,
+            ..OutputOptions::default()
+        };
+
+        let mut buf = Vec::new();
+        write_csv(&balances, &options, &mut buf)?;
+        let text = String::from_utf8(buf)?;
+        let clients: Vec<&str> = text.lines().skip(1).map(|l| &l[..1]).collect();
+        assert_eq!(clients, vec!["1", "2"]);
+        assert_eq!(
+            truncation_note(balances.len(), &options).as_deref(),
+            Some("output truncated to 2 of 5 clients (--max-output-rows)")
+        );
+        assert_eq!(truncation_note(2, &options), None);
+        assert_eq!(truncation_note(5, &OutputOptions::default()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_column_order() -> Result<()> {
+        let mut balances = HashMap::new();
+        balances.insert(
+            3,
+            ClientBalance {
+                available: dec!(1.5),
+                held: dec!(2),
+                locked: true,
+                lock_reason: None,
+            },
+        );
+        let options = OutputOptions {
+            columns: Some(parse_columns("client,locked,total,available,held")?),
+            ..OutputOptions::default()
+        };
+
+        let mut buf = Vec::new();
+        write_csv(&balances, &options, &mut buf)?;
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "client,locked,total,available,held\n3,true,3.5000,1.5000,2.0000\n"
+        );
+
+        let err = parse_columns("client,balance").unwrap_err();
+        assert!(err.to_string().contains("Unknown column 'balance'"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pad_client() -> Result<()> {
+        let mut balances = HashMap::new();
+        balances.insert(42, ClientBalance::new());
+        let options = OutputOptions {
+            pad_client: Some(5),
+            ..OutputOptions::default()
+        };
+        assert_eq!(options.rows().csv_client(42), "00042");
+        assert_eq!(OutputOptions::default().rows().csv_client(42), "42");
+
+        let mut buf = Vec::new();
+        write_csv(&balances, &options, &mut buf)?;
+        let text = String::from_utf8(buf)?;
+        assert_eq!(
+            text.lines().nth(1),
+            Some("00042,0.0000,0.0000,0.0000,false")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_client_labels() -> Result<()> {
+        let mut names = ClientInterner::new();
+        let mut balances = HashMap::new();
+        balances.insert(names.intern("acct-b")?, ClientBalance::new());
+        balances.insert(names.intern("acct-a")?, ClientBalance::new());
+        let options = OutputOptions {
+            client_names: Some(names),
+            ..OutputOptions::default()
+        };
+
+        let mut csv = Vec::new();
+        write_csv(&balances, &options, &mut csv)?;
+        let csv = String::from_utf8(csv)?;
+        // Listed by name, not by the order the names were first seen in.
+        assert!(csv.contains("\nacct-a,0.0000,0.0000,0.0000,false\n"));
+        assert!(csv.ends_with("\nacct-b,0.0000,0.0000,0.0000,false\n"));
+
+        let mut json = Vec::new();
+        write_ndjson(&balances, &options, &mut json)?;
+        let first: serde_json::Value =
+            serde_json::from_str(String::from_utf8(json)?.lines().next().unwrap())?;
+        assert_eq!(first["client"], "acct-a");
+        Ok(())
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet_round_trip() -> Result<()> {
+        use arrow_array::{Array, BooleanArray, Decimal128Array, UInt16Array};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let mut balances = HashMap::new();
+        balances.insert(
+            2,
+            ClientBalance {
+                available: dec!(1.5),
+                held: dec!(0.25),
+                locked: true,
+                lock_reason: None,
+            },
+        );
+        balances.insert(
+            1,
+            ClientBalance {
+                available: dec!(10.12345),
+                held: dec!(0),
+                locked: false,
+                lock_reason: None,
+            },
+        );
+
+        let file = tempfile::NamedTempFile::new()?;
+        write_parquet(&balances, &OutputOptions::default(), file.reopen()?)?;
+
+        let mut reader = ParquetRecordBatchReaderBuilder::try_new(file.reopen()?)?.build()?;
+        let batch = reader.next().unwrap()?;
+        assert_eq!(batch.num_rows(), 2);
+
+        let column = |name: &str| batch.column_by_name(name).unwrap().clone();
+        let clients = column("client");
+        let clients = clients.as_any().downcast_ref::<UInt16Array>().unwrap();
+        assert_eq!(clients.values(), &[1, 2]);
+
+        let decimal_at = |name: &str, row: usize| {
+            let array = column(name);
+            let array = array.as_any().downcast_ref::<Decimal128Array>().unwrap();
+            Decimal::from_i128_with_scale(array.value(row), 4)
+        };
+        assert_eq!(decimal_at("available", 0), dec!(10.1234));
+        assert_eq!(decimal_at("held", 1), dec!(0.25));
+        assert_eq!(decimal_at("total", 1), dec!(1.75));
+
+        let locked = column("locked");
+        let locked = locked.as_any().downcast_ref::<BooleanArray>().unwrap();
+        assert!(!locked.value(0));
+        assert!(locked.value(1));
+        assert_eq!(locked.len(), 2);
+        Ok(())
+    }
+}
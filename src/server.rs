@@ -0,0 +1,228 @@
+use anyhow::{anyhow, Context, Result};
+use csv::{Reader, StringRecord};
+use log::{info, warn};
+use payments_engine::engine::{self, Engine};
+use payments_engine::output::{self, ClientData, OutputOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Accepts connections on `listener` one at a time, feeding every row they send
+/// through `engine`, so balances accumulate across connections until the process
+/// is stopped. Rows are split on `options.delimiter`, as `--delimiter` input is, and
+/// query replies are formatted per `options`, as the CSV output would be.
+pub fn serve(listener: TcpListener, engine: &mut Engine, options: &OutputOptions) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream.context("Failed to accept connection")?;
+        let peer = stream.peer_addr().context("Failed to read peer address")?;
+        info!("connection from {}", peer);
+        if let Err(err) = handle_connection(engine, options, stream) {
+            warn!("connection from {} closed: {:#}", peer, err);
+        }
+    }
+    Ok(())
+}
+
+/// Reads newline-delimited CSV rows off `stream` and applies each to `engine`.
+///
+/// Rows are read as `type,client,tx,amount` unless the first line is a header naming
+/// the columns. A `query,<client>` line is answered with that client's balance as an
+/// output row (`client,available,held,total,locked`); a row that fails to parse or,
+/// under `--strict`, to apply is answered with `error: <message>`. Applied and
+/// skipped rows get no reply.
+fn handle_connection(
+    engine: &mut Engine,
+    options: &OutputOptions,
+    stream: TcpStream,
+) -> Result<()> {
+    let mut reply = stream.try_clone().context("Failed to clone connection")?;
+    let mut headers = StringRecord::from(engine::REQUIRED_COLUMNS.to_vec());
+    for (i, line) in BufReader::new(stream).lines().enumerate() {
+        let line = line.context("Failed to read from connection")?;
+        let Some(row) = split_row(&line, options.delimiter)? else {
+            continue;
+        };
+        if i == 0 && row.get(0) == Some("type") {
+            let mut rdr = line_reader(&line, options.delimiter, true);
+            if let Err(err) = engine::check_header(&mut rdr) {
+                writeln!(reply, "error: {:#}", err).context("Failed to write to connection")?;
+                return Err(err);
+            }
+            headers = row;
+            continue;
+        }
+        let answer = match row.get(0) {
+            Some("query") => query(engine, options, &row),
+            _ => engine
+                .parse_row(&headers, &row)
+                .and_then(|record| engine.apply(&record))
+                .map(|_| None),
+        };
+        match answer {
+            Ok(Some(text)) => write!(reply, "{}", text),
+            Ok(None) => Ok(()),
+            Err(err) => writeln!(reply, "error: {:#}", err),
+        }
+        .context("Failed to write to connection")?;
+    }
+    Ok(())
+}
+
+/// A csv reader over one line, per `engine::reader_builder` with `delimiter` in
+/// place of the comma when given.
+fn line_reader(line: &str, delimiter: Option<u8>, has_headers: bool) -> Reader<&[u8]> {
+    let mut builder = engine::reader_builder();
+    builder.has_headers(has_headers);
+    if let Some(delimiter) = delimiter {
+        builder.delimiter(delimiter);
+    }
+    builder.from_reader(line.as_bytes())
+}
+
+/// One line as trimmed CSV fields; `None` for a blank line.
+fn split_row(line: &str, delimiter: Option<u8>) -> Result<Option<StringRecord>> {
+    let mut rdr = line_reader(line, delimiter, false);
+    rdr.records()
+        .next()
+        .transpose()
+        .context("Failed to parse row")
+}
+
+/// The balance row answering a `query,<client>` line. Under `--string-clients` the
+/// client is named as in the input.
+fn query(engine: &Engine, options: &OutputOptions, row: &StringRecord) -> Result<Option<String>> {
+    let invalid = || {
+        anyhow!(
+            "Invalid query '{}' (expected query,<client>)",
+            row.as_slice()
+        )
+    };
+    let name = row
+        .get(1)
+        .filter(|name| !name.is_empty())
+        .ok_or_else(invalid)?;
+    let client = match &engine.client_names {
+        Some(names) => names.id(name),
+        None => Some(name.parse::<u16>().map_err(|_| invalid())?),
+    };
+    let Some((client, balance)) =
+        client.and_then(|client| Some((client, engine.client_balances.get(&client)?)))
+    else {
+        return Err(anyhow!("unknown client {}", name));
+    };
+    let clients = ClientData {
+        names: engine.client_names.as_ref(),
+        flows: engine.client_flows.as_ref(),
+        hashes: engine.client_hashes.as_ref(),
+    };
+    output::format_csv_row(client, balance, options, clients).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use payments_engine::config::EngineConfig;
+    use std::thread;
+
+    #[test]
+    fn test_serve_over_loopback() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || {
+            let mut engine = Engine::new(EngineConfig::default());
+            serve(listener, &mut engine, &OutputOptions::default())
+        });
+
+        {
+            let stream = TcpStream::connect(addr)?;
+            let mut replies = BufReader::new(stream.try_clone()?).lines();
+            let send = |line: &str| writeln!(&stream, "{}", line);
+            send("deposit,1,1,10.0")?;
+            send("withdrawal,1,2,2.5")?;
+            send("deposit,2,3,1.0")?;
+            send("dispute,2,3,")?;
+            send("query,1")?;
+            assert_eq!(replies.next().unwrap()?, "1,7.5000,0.0000,7.5000,false");
+            send("query,2")?;
+            assert_eq!(replies.next().unwrap()?, "2,0.0000,1.0000,1.0000,false");
+            send("deposit,1,x,1.0")?;
+            assert!(replies.next().unwrap()?.starts_with("error: "));
+            send("query,9")?;
+            assert_eq!(replies.next().unwrap()?, "error: unknown client 9");
+        }
+
+        // A later connection sees the same ledger.
+        let stream = TcpStream::connect(addr)?;
+        let mut replies = BufReader::new(stream.try_clone()?).lines();
+        writeln!(&stream, "type,client,tx,amount\nchargeback,2,3,\nquery,2")?;
+        assert_eq!(replies.next().unwrap()?, "2,0.0000,0.0000,0.0000,true");
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_uses_output_precision() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let options = OutputOptions {
+            precision: Some(2),
+            ..OutputOptions::default()
+        };
+        thread::spawn(move || {
+            let mut engine = Engine::new(EngineConfig::default());
+            serve(listener, &mut engine, &options)
+        });
+
+        let stream = TcpStream::connect(addr)?;
+        let mut replies = BufReader::new(stream.try_clone()?).lines();
+        writeln!(&stream, "deposit,1,1,7.005\nquery,1")?;
+        assert_eq!(replies.next().unwrap()?, "1,7.00,0.00,7.00,false");
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_tab_delimited_rows() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let options = OutputOptions {
+            delimiter: Some(b'\t'),
+            ..OutputOptions::default()
+        };
+        thread::spawn(move || {
+            let mut engine = Engine::new(EngineConfig::default());
+            serve(listener, &mut engine, &options)
+        });
+
+        let stream = TcpStream::connect(addr)?;
+        let mut replies = BufReader::new(stream.try_clone()?).lines();
+        writeln!(
+            &stream,
+            "type\tclient\ttx\tamount\ndeposit\t1\t1\t3.0\nquery\t1"
+        )?;
+        assert_eq!(replies.next().unwrap()?, "1\t3.0000\t0.0000\t3.0000\tfalse");
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_string_client() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let config = EngineConfig::builder().string_clients(true).build();
+        thread::spawn(move || {
+            serve(
+                listener,
+                &mut Engine::new(config),
+                &OutputOptions::default(),
+            )
+        });
+
+        let stream = TcpStream::connect(addr)?;
+        let mut replies = BufReader::new(stream.try_clone()?).lines();
+        writeln!(
+            &stream,
+            "deposit,zed,1,2.0\ndeposit,alice,2,1.5\nquery,alice"
+        )?;
+        assert_eq!(replies.next().unwrap()?, "alice,1.5000,0.0000,1.5000,false");
+        writeln!(&stream, "query,bob")?;
+        assert_eq!(replies.next().unwrap()?, "error: unknown client bob");
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::fmt;
+
+/// Constant-memory summary of a stream of amounts: min, max, mean and an
+/// approximate median.
+#[derive(Debug, Clone, Default)]
+pub struct AmountStats {
+    pub count: u64,
+    pub min: Option<Decimal>,
+    pub max: Option<Decimal>,
+    sum: Decimal,
+    median: P2Median,
+}
+
+impl AmountStats {
+    pub fn record(&mut self, amount: Decimal) {
+        self.count += 1;
+        self.min = Some(self.min.map_or(amount, |min| min.min(amount)));
+        self.max = Some(self.max.map_or(amount, |max| max.max(amount)));
+        // Saturating keeps adversarial inputs from aborting a profiling run; the mean
+        // is then only a lower bound.
+        self.sum = self.sum.saturating_add(amount);
+        self.median.record(amount.to_f64().unwrap_or(0.0));
+    }
+
+    pub fn mean(&self) -> Option<Decimal> {
+        (self.count > 0).then(|| self.sum / Decimal::from(self.count))
+    }
+
+    pub fn approximate_median(&self) -> Option<f64> {
+        self.median.estimate()
+    }
+}
+
+impl fmt::Display for AmountStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.min, self.max, self.mean(), self.approximate_median()) {
+            (Some(min), Some(max), Some(mean), Some(median)) => write!(
+                f,
+                "count={} min={} max={} mean={:.4} median~{:.4}",
+                self.count,
+                min,
+                max,
+                mean.round_dp(4),
+                median
+            ),
+            _ => write!(f, "count=0"),
+        }
+    }
+}
+
+/// Deposit and withdrawal amount profiles, reported under `--amount-stats`.
+///
+/// Every deposit/withdrawal row carrying an amount is counted, whether or not the
+/// operation was applied, since the point is to profile the feed.
+#[derive(Debug, Clone, Default)]
+pub struct AmountSummary {
+    pub deposits: AmountStats,
+    pub withdrawals: AmountStats,
+}
+
+/// P² streaming estimate of the median (Jain & Chlamtac, 1985).
+///
+/// Tracks five markers whose heights converge on the minimum, the 25th/50th/75th
+/// percentiles and the maximum, adjusting them with piecewise-parabolic
+/// interpolation as values arrive. Uses `f64` since the result is an estimate.
+#[derive(Debug, Clone, Default)]
+struct P2Median {
+    initial: Vec<f64>,
+    heights: [f64; 5],
+    positions: [f64; 5],
+    desired: [f64; 5],
+}
+
+impl P2Median {
+    const INCREMENTS: [f64; 5] = [0.0, 0.25, 0.5, 0.75, 1.0];
+
+    fn record(&mut self, x: f64) {
+        if self.initial.len() < 5 {
+            self.initial.push(x);
+            if self.initial.len() == 5 {
+                self.initial.sort_by(f64::total_cmp);
+                self.heights.copy_from_slice(&self.initial);
+                self.positions = [1.0, 2.0, 3.0, 4.0, 5.0];
+                self.desired = [1.0, 2.0, 3.0, 4.0, 5.0];
+            }
+            return;
+        }
+
+        let h = &mut self.heights;
+        let k = if x < h[0] {
+            h[0] = x;
+            0
+        } else if x >= h[4] {
+            h[4] = x;
+            3
+        } else {
+            (0..4).find(|&i| x < h[i + 1]).unwrap_or(3)
+        };
+        for position in &mut self.positions[k + 1..] {
+            *position += 1.0;
+        }
+        for (desired, increment) in self.desired.iter_mut().zip(Self::INCREMENTS) {
+            *desired += increment;
+        }
+
+        for i in 1..4 {
+            let d = self.desired[i] - self.positions[i];
+            let (n, q) = (&mut self.positions, &mut self.heights);
+            if (d >= 1.0 && n[i + 1] - n[i] > 1.0) || (d <= -1.0 && n[i - 1] - n[i] < -1.0) {
+                let d = d.signum();
+                let parabolic = q[i]
+                    + d / (n[i + 1] - n[i - 1])
+                        * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                            + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]));
+                q[i] = if q[i - 1] < parabolic && parabolic < q[i + 1] {
+                    parabolic
+                } else {
+                    let j = if d > 0.0 { i + 1 } else { i - 1 };
+                    q[i] + d * (q[j] - q[i]) / (n[j] - n[i])
+                };
+                n[i] += d;
+            }
+        }
+    }
+
+    fn estimate(&self) -> Option<f64> {
+        if self.initial.len() == 5 {
+            return Some(self.heights[2]);
+        }
+        let mut seen = self.initial.clone();
+        seen.sort_by(f64::total_cmp);
+        match seen.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(seen[n / 2]),
+            n => Some((seen[n / 2 - 1] + seen[n / 2]) / 2.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_min_max_mean() {
+        let mut stats = AmountStats::default();
+        for amount in [dec!(2.5), dec!(10), dec!(0.5), dec!(7)] {
+            stats.record(amount);
+        }
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.min, Some(dec!(0.5)));
+        assert_eq!(stats.max, Some(dec!(10)));
+        assert_eq!(stats.mean(), Some(dec!(5)));
+        assert_eq!(stats.approximate_median(), Some(4.75));
+    }
+
+    #[test]
+    fn test_empty_stats() {
+        let stats = AmountStats::default();
+        assert_eq!(stats.mean(), None);
+        assert_eq!(stats.approximate_median(), None);
+        assert_eq!(stats.to_string(), "count=0");
+    }
+
+    #[test]
+    fn test_streaming_median_converges() {
+        let mut stats = AmountStats::default();
+        // 1..=1001 in a scrambled but deterministic order.
+        for i in 0..1001u64 {
+            stats.record(Decimal::from((i * 617) % 1001 + 1));
+        }
+        let median = stats.approximate_median().unwrap();
+        assert!((median - 501.0).abs() < 15.0, "median estimate {}", median);
+        assert_eq!(stats.mean(), Some(dec!(501)));
+    }
+}